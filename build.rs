@@ -11,6 +11,11 @@ fn main() {
     println!("cargo:rustc-link-lib=OsiClp");
     println!("cargo:rustc-link-lib=Cgl");
 
+    // SCS backend, only when the feature is on
+    if std::env::var("CARGO_FEATURE_SCS").is_ok() {
+        println!("cargo:rustc-link-lib=scsdir");
+    }
+
     // Only rebuild if build.rs changes
     println!("cargo:rerun-if-changed=build.rs");
 }
@@ -1,194 +1,223 @@
-use env_logger;
-use log::{self, log_enabled};
-use totsu::prelude::*;
-use totsu::{MatBuild, ProbSOCP};
-
-type La = FloatGeneric<f64>;
+use ethers::types::{Address, U256};
+use std::collections::HashMap;
+use CFMM_covex_optimization::cfmm::{BalancerPool, Cfmm, ConstantSumPool, UniV2Pool};
+use CFMM_covex_optimization::export::write_cbf;
+use CFMM_covex_optimization::order_book::OrderBook;
+use CFMM_covex_optimization::robust::{band_scenarios, RobustObjective};
+use CFMM_covex_optimization::rounding::{required_holdings, round_trades, Slippage};
+use CFMM_covex_optimization::router::Router;
+use CFMM_covex_optimization::solver::{
+    Backend, Session, SolveOutcome, SolverConfig, SolverError,
+};
+
+// Six-decimal tokens: base units per whole token
+const UNIT: u64 = 1_000_000;
 
 fn main() {
-    const N_TOKENS: usize = 4;
-    const N_CFMMS: usize = 5;
-
-    let reserves: Vec<Vec<f64>> = vec![
-        vec![4.0, 4.0, 4.0, 4.0], // Balancer pool
-        vec![10.0, 1.0],          // UniswapV2 pool: TOKEN-0/TOKEN-1
-        vec![1.0, 5.0],           // UniswapV2 pool: TOKEN-1/TOKEN-2
-        vec![40.0, 50.0],         // UniswapV2 pool: TOKEN-2/TOKEN-3
-        vec![10.0, 10.0],         // Constant Sum pool: TOKEN-2/TOKEN-3
+    let tokens: Vec<Address> = (0..4u8).map(|i| Address::from([i; 20])).collect();
+
+    // Off-chain book for TOKEN-0/TOKEN-1, routed next to the AMMs
+    let book_path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "order_book.json".to_string());
+    let book = OrderBook::load_from_file(&book_path).expect("Failed on loading order book");
+
+    let pools = vec![
+        // Balancer pool, equal weights
+        Cfmm::Balancer(BalancerPool {
+            address: Address::from([0x10; 20]),
+            tokens: tokens.clone(),
+            reserves: vec![U256::from(4 * UNIT); 4],
+            weights: vec![0.25; 4],
+            router_fee: U256::from(20),
+        }),
+        // UniswapV2 pool: TOKEN-0/TOKEN-1
+        Cfmm::from(UniV2Pool::new(
+            Address::from([0x11; 20]),
+            tokens[0],
+            tokens[1],
+            U256::from(10 * UNIT),
+            U256::from(UNIT),
+        )),
+        // UniswapV2 pool: TOKEN-1/TOKEN-2
+        Cfmm::from(UniV2Pool::new(
+            Address::from([0x12; 20]),
+            tokens[1],
+            tokens[2],
+            U256::from(UNIT),
+            U256::from(5 * UNIT),
+        )),
+        // UniswapV2 pool: TOKEN-2/TOKEN-3
+        Cfmm::from(UniV2Pool::new(
+            Address::from([0x13; 20]),
+            tokens[2],
+            tokens[3],
+            U256::from(40 * UNIT),
+            U256::from(50 * UNIT),
+        )),
+        // Constant Sum pool: TOKEN-2/TOKEN-3
+        Cfmm::ConstantSum(ConstantSumPool {
+            address: Address::from([0x14; 20]),
+            token0: tokens[2],
+            token1: tokens[3],
+            reserve0: U256::from(10 * UNIT),
+            reserve1: U256::from(10 * UNIT),
+            router_fee: U256::from(10),
+            price: 1.0,
+            max_out: None,
+        }),
+        Cfmm::OrderBook(book), // Order book: TOKEN-0/TOKEN-1
     ];
 
-    let fees: Vec<f64> = vec![0.998, 0.997, 0.997, 0.997, 0.999];
     let market_value: Vec<f64> = vec![1.0, 12.0, 1.8, 3.5];
+    let prices: HashMap<Address, f64> = tokens.iter().cloned().zip(market_value).collect();
 
-    // Create variables
-    let n_vars = 2 * N_TOKENS * N_CFMMS;
-
-    // Objective vector `f`
-    let mut vec_f = MatBuild::new(MatType::General(n_vars, 1));
-    vec_f.set_by_fn(|r, _| {
-        let token = (r % (2 * N_TOKENS)) / 2;
-        if r % 2 == 0 {
-            -market_value[token] // For lambda (negative because we're minimizing)
-        } else {
-            market_value[token] // For delta
-        }
-    });
-
-    // Create SOCP constraint matrices
-    let mut mats_g = Vec::new();
-    let mut vecs_h = Vec::new();
-    let mut vecs_c = Vec::new();
-    let mut scls_d = Vec::new();
-
-    for i in 0..N_CFMMS {
-        let pool_reserves = &reserves[i];
-        let n_pool_tokens = pool_reserves.len();
+    // What the wallet holds; the route has to be paid for from these
+    let holdings: HashMap<Address, f64> = HashMap::from([
+        (tokens[0], 10.0 * UNIT as f64),
+        (tokens[2], 5.0 * UNIT as f64),
+    ]);
+    let router = Router::new(pools, &prices).with_inventory(&holdings);
 
-        match i {
-            0 => {
-                // Balancer Pool (geometric mean)
-                let mut g = MatBuild::new(MatType::General(n_pool_tokens + 1, n_vars));
-                let mut h = MatBuild::new(MatType::General(n_pool_tokens + 1, 1));
-                let mut c = MatBuild::new(MatType::General(n_vars, 1));
+    // The geometric-mean pool needs exponential cones, so default to Clarabel;
+    // CFMM_SOLVER still picks another backend.
+    let mut config = SolverConfig::from_env_or(Backend::Clarabel).expect("Unknown solver backend");
+    config.max_iter = 2000000;
 
-                for j in 0..n_pool_tokens {
-                    let lambda_idx = 2 * (i * N_TOKENS + j);
-                    let delta_idx = lambda_idx + 1;
+    // Kept for the next block
+    let mut session = Session::new(config.clone());
 
-                    g[(j, lambda_idx)] = -1.0 / (pool_reserves[j] as f64).sqrt();
-                    g[(j, delta_idx)] = fees[i] / (pool_reserves[j] as f64).sqrt();
-                    h[(j, 0)] = (pool_reserves[j] as f64).sqrt();
-                    g[(n_pool_tokens, lambda_idx)] = -1.0;
-                    g[(n_pool_tokens, delta_idx)] = fees[i];
+    match router.solve_in(&mut session) {
+        Ok(solution) => {
+            println!("Optimal solution found:");
+            for (i, trade) in solution.trades.iter().enumerate() {
+                for (j, token) in trade.tokens.iter().enumerate() {
+                    let lambda = trade.received[j];
+                    let delta = trade.tendered[j];
+                    if lambda.abs() > 1e-6 || delta.abs() > 1e-6 {
+                        println!(
+                            "CFMM {}, Token {}: lambda = {:.6}, delta = {:.6}",
+                            i,
+                            router.token_index(*token),
+                            lambda,
+                            delta
+                        );
+                    }
                 }
-                h[(n_pool_tokens, 0)] = 0.0; // Allow for slight imbalance
-
-                mats_g.push(g);
-                vecs_h.push(h);
-                vecs_c.push(c);
-                scls_d.push(0.0);
             }
-            1..=3 => {
-                // UniswapV2 Pools (constant product)
-                let mut g = MatBuild::new(MatType::General(3, n_vars));
-                let mut h = MatBuild::new(MatType::General(3, 1));
-                let mut c = MatBuild::new(MatType::General(n_vars, 1));
-
-                let lambda_idx_0 = 2 * (i * N_TOKENS);
-                let delta_idx_0 = lambda_idx_0 + 1;
-                let lambda_idx_1 = lambda_idx_0 + 2;
-                let delta_idx_1 = lambda_idx_1 + 1;
-
-                g[(0, lambda_idx_0)] = -1.0;
-                g[(0, delta_idx_0)] = fees[i];
-                g[(1, lambda_idx_1)] = -1.0;
-                g[(1, delta_idx_1)] = fees[i];
-                g[(2, lambda_idx_0)] = -0.5 / (pool_reserves[0] as f64).sqrt();
-                g[(2, delta_idx_0)] = 0.5 * fees[i] / (pool_reserves[0] as f64).sqrt();
-                g[(2, lambda_idx_1)] = -0.5 / (pool_reserves[1] as f64).sqrt();
-                g[(2, delta_idx_1)] = 0.5 * fees[i] / (pool_reserves[1] as f64).sqrt();
 
-                h[(0, 0)] = pool_reserves[0];
-                h[(1, 0)] = pool_reserves[1];
-                h[(2, 0)] = (pool_reserves[0] * pool_reserves[1] as f64).sqrt() * 0.996; // Allow for slight imbalance
+            // Print the objective value
+            println!("Objective value: {:.6}", solution.value);
 
-                mats_g.push(g);
-                vecs_h.push(h);
-                vecs_c.push(c);
-                scls_d.push(0.0);
+            // Dual prices: what each token is worth at the optimum, and how
+            // each pool values its own tokens after the trade
+            for (t, price) in solution.token_prices.iter().enumerate() {
+                println!("Token {}: marginal price = {:.6}", t, price);
             }
-            4 => {
-                // Constant Sum Pool (linear constraint)
-                let mut g = MatBuild::new(MatType::General(1, n_vars));
-                let mut h = MatBuild::new(MatType::General(1, 1));
-                let mut c = MatBuild::new(MatType::General(n_vars, 1));
-
-                for j in 0..n_pool_tokens {
-                    let lambda_idx = 2 * (i * N_TOKENS + j);
-                    let delta_idx = lambda_idx + 1;
-
-                    g[(0, lambda_idx)] = -1.0;
-                    g[(0, delta_idx)] = fees[i];
+            for (i, trade) in solution.trades.iter().enumerate() {
+                for (token, price) in trade.tokens.iter().zip(&solution.pool_prices[i]) {
+                    println!(
+                        "CFMM {}, Token {}: shadow price = {:.6}",
+                        i,
+                        router.token_index(*token),
+                        price
+                    );
+                }
+            }
+            // and what each pool quotes in its first token once it has traded
+            for (i, trade) in solution.trades.iter().enumerate() {
+                let rates = &solution.post_trade_rates[i];
+                for (token, rate) in trade.tokens.iter().zip(rates).skip(1) {
+                    println!(
+                        "CFMM {}, Token {}: post-trade rate = {:.6}",
+                        i,
+                        router.token_index(*token),
+                        rate
+                    );
+                }
+            }
+            println!("{}", solution.verification);
+
+            // Whole token units, replayed hop by hop; each hop still clears
+            // if its pool moves 0.5% against it before inclusion
+            let slippage = Slippage {
+                tolerance: 0.005,
+                reserve_shift: 0.005,
+            };
+            let plan = required_holdings(&solution.trades).and_then(|holdings| {
+                round_trades(&router.pools, &solution.trades, &holdings, &slippage)
+            });
+            match plan {
+                Ok(plan) => {
+                    println!("Executable plan:");
+                    print!("{}", plan);
+                }
+                Err(e) => println!("Cannot round solution: {}", e),
+            }
+        }
+        Err(SolverError::Unverified(verification)) => {
+            println!("Solution does not hold up against the pools:");
+            println!("{}", verification);
+        }
+        Err(e) => {
+            println!("Error: {}", e);
+            if let SolverError::NotSolved(_, outcome) = &e {
+                // reproducible with any CBF reader
+                match write_cbf(&router.build(config.tolerance), "routing.cbf") {
+                    Ok(()) => println!("Problem written to routing.cbf"),
+                    Err(e) => println!("Could not write routing.cbf: {}", e),
+                }
+                if let SolveOutcome::Infeasible { .. } = outcome.as_ref() {
+                    match router.explain_infeasibility(&config) {
+                        Ok(Some(conflict)) => {
+                            println!("These constraints cannot hold together:");
+                            for name in conflict {
+                                println!("  {}", name);
+                            }
+                        }
+                        Ok(None) => println!("Feasible once the objective is dropped"),
+                        Err(e) => println!("Could not explain infeasibility: {}", e),
+                    }
                 }
-
-                h[(0, 0)] = pool_reserves.iter().sum::<f64>() * 0.999; // Allow for slight imbalance
-
-                mats_g.push(g);
-                vecs_h.push(h);
-                vecs_c.push(c);
-                scls_d.push(0.0);
             }
-            _ => unreachable!(),
         }
     }
 
-    // Add non-negativity constraints for all variables
-    let mut g_nonneg = MatBuild::new(MatType::General(n_vars, n_vars));
-    let mut h_nonneg = MatBuild::new(MatType::General(n_vars, 1));
-    for i in 0..n_vars {
-        g_nonneg[(i, i)] = 1.0;
-        h_nonneg[(i, 0)] = 0.0;
-    }
-    mats_g.push(g_nonneg);
-    vecs_h.push(h_nonneg);
-    vecs_c.push(MatBuild::new(MatType::General(n_vars, 1)));
-    scls_d.push(0.0);
-
-    // Add a constraint to limit the total amount traded
-    let mut g_limit = MatBuild::new(MatType::General(1, n_vars));
-    let mut h_limit = MatBuild::new(MatType::General(1, 1));
-    for i in 0..n_vars {
-        g_limit[(0, i)] = 1.0;
+    // Buying exactly half a token 1 with as little of token 0 as possible
+    let half = 0.5 * UNIT as f64;
+    match router.solve_exact_output(tokens[0], tokens[1], half, &config) {
+        Ok(solution) => println!(
+            "Buy 0.5 of token 1: spend {:.6} of token 0",
+            -solution.value / UNIT as f64
+        ),
+        Err(e) => println!("Exact-output routing: {}", e),
     }
-    h_limit[(0, 0)] = reserves.iter().flatten().sum::<f64>() * 0.9; // Increase limit to 75% of total reserves
-    mats_g.push(g_limit);
-    vecs_h.push(h_limit);
-    vecs_c.push(MatBuild::new(MatType::General(n_vars, 1)));
-    scls_d.push(0.0);
-
-    // Create empty matrices for equality constraints (we don't have any in this problem)
-    let mat_a = MatBuild::new(MatType::General(0, n_vars));
-    let vec_b = MatBuild::new(MatType::General(0, 1));
-
-    // Create the ProbSOCP struct
-    let mut prob = ProbSOCP::new(vec_f.clone(), mats_g, vecs_h, vecs_c, scls_d, mat_a, vec_b);
-
-    // Set up the Totsu solver
-    let mut solver = Solver::<La>::new();
-
-    // Adjust solver parameters
-    solver = solver.par(|p| {
-        p.eps_acc = 1e-3;
-        p.eps_inf = 1e-3;
-        p.max_iter = Some(2000000);
-    });
 
-    // Generate the problem components and solve
-    let (op_c, op_a, op_b, cone, mut work) = prob.problem();
-    match solver.solve((op_c, op_a, op_b, cone, &mut work)) {
-        Ok((x, _y)) => {
-            println!("Optimal solution found:");
-            for i in 0..N_CFMMS {
-                for j in 0..N_TOKENS {
-                    let lambda = x[2 * (i * N_TOKENS + j)];
-                    let delta = x[2 * (i * N_TOKENS + j) + 1];
-                    if lambda.abs() > 1e-6 || delta.abs() > 1e-6 {
-                        println!(
-                            "CFMM {}, Token {}: lambda = {:.6}, delta = {:.6}",
-                            i, j, lambda, delta
-                        );
-                    }
-                }
+    // Reserves known to 1%: one set of inputs for every scenario
+    let scenarios = band_scenarios(&router, 0.01);
+    for objective in [RobustObjective::WorstCase, RobustObjective::Expected] {
+        match router.solve_robust(&scenarios, objective, &config) {
+            Ok(robust) => {
+                println!("Robust routing, {:?}:", objective);
+                print!("{}", robust);
             }
-
-            // Calculate and print the objective value
-            let obj_val: f64 = x
-                .iter()
-                .enumerate()
-                .map(|(i, &val)| -vec_f[(i, 0)] * val)
-                .sum();
-            println!("Objective value: {:.6}", obj_val);
+            Err(e) => println!("Robust routing, {:?}: {}", objective, e),
         }
-        Err(e) => println!("Error: {:?}", e),
+    }
+
+    // Next block: the same pools, one reserve moved. The session starts from
+    // the solution above and, on SCS, keeps the set-up of the first solve.
+    let mut pools = router.pools.clone();
+    if let Cfmm::UniV2(pool) = &mut pools[1] {
+        pool.reserve0 += U256::one();
+    }
+    let next = Router::new(pools, &prices).with_inventory(&holdings);
+    println!(
+        "Next block, same structure: {}",
+        session.reuses(&next.build(config.tolerance))
+    );
+    match next.solve_in(&mut session) {
+        Ok(solution) => println!("Next block objective value: {:.6}", solution.value),
+        Err(e) => println!("Next block: {}", e),
     }
 }
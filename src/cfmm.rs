@@ -0,0 +1,1129 @@
+use ethers::types::Address;
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+
+use crate::order_book::OrderBook;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UniV2Pool {
+    pub address: Address,
+
+    pub token0: Address,
+    pub token1: Address,
+
+    pub reserve0: U256,
+    pub reserve1: U256,
+
+    // router fee
+    pub router_fee: U256,
+    //  token tax when token0 is in
+    pub fees0: U256,
+    //  token tax when token1 is in
+    pub fees1: U256,
+}
+
+impl UniV2Pool {
+    /// Pair with the 0.3% router fee and no token taxes.
+    pub fn new(
+        address: Address,
+        token0: Address,
+        token1: Address,
+        reserve0: U256,
+        reserve1: U256,
+    ) -> Self {
+        Self {
+            address,
+            token0,
+            token1,
+            reserve0,
+            reserve1,
+            router_fee: U256::from(30),
+            fees0: U256::zero(),
+            fees1: U256::zero(),
+        }
+    }
+
+    /// `new` at address `[address; 20]`, the pair the unit tests share.
+    #[cfg(test)]
+    pub(crate) fn fixture(
+        address: u8,
+        token0: Address,
+        token1: Address,
+        reserves: (u64, u64),
+    ) -> Cfmm {
+        Cfmm::UniV2(Self::new(
+            Address::from([address; 20]),
+            token0,
+            token1,
+            U256::from(reserves.0),
+            U256::from(reserves.1),
+        ))
+    }
+
+    pub fn fee(&self) -> f64 {
+        1.0 - (self.router_fee.as_u64() as f64 / 10000.0)
+    }
+
+    pub fn get_amount_out(&self, token_in: Address, amount_in: f64) -> f64 {
+        let (reserve_in, reserve_out) = if token_in == self.token0 {
+            (self.reserve0.as_u128() as f64, self.reserve1.as_u128() as f64)
+        } else {
+            (self.reserve1.as_u128() as f64, self.reserve0.as_u128() as f64)
+        };
+        let fee = self.fee();
+
+        (reserve_out * amount_in * fee) / (reserve_in + amount_in * fee)
+    }
+
+    /// `getAmountOut` in integer arithmetic, rounded down like the pair
+    /// contract does, with the fee in basis points.
+    pub fn get_amount_out_exact(&self, token_in: Address, amount_in: U256) -> U256 {
+        let (reserve_in, reserve_out) = if token_in == self.token0 {
+            (self.reserve0, self.reserve1)
+        } else {
+            (self.reserve1, self.reserve0)
+        };
+        if amount_in.is_zero() {
+            return U256::zero();
+        }
+
+        let amount_in_with_fee = amount_in * (U256::from(10000) - self.router_fee);
+        let numerator = amount_in_with_fee * reserve_out;
+        let denominator = reserve_in * U256::from(10000) + amount_in_with_fee;
+        numerator / denominator
+    }
+
+    /// Input of `token_in` that buys `amount_out`; infinite when the pool
+    /// does not hold that much.
+    pub fn get_amount_in(&self, token_in: Address, amount_out: f64) -> f64 {
+        let (reserve_in, reserve_out) = if token_in == self.token0 {
+            (self.reserve0.as_u128() as f64, self.reserve1.as_u128() as f64)
+        } else {
+            (self.reserve1.as_u128() as f64, self.reserve0.as_u128() as f64)
+        };
+        if amount_out >= reserve_out {
+            return f64::INFINITY;
+        }
+
+        reserve_in * amount_out / ((reserve_out - amount_out) * self.fee())
+    }
+
+    /// `getAmountIn` of the router library: an integer input that buys at
+    /// least `amount_out` (rounded up, so at most one unit more than needed);
+    /// `None` when the pool does not hold that much.
+    pub fn get_amount_in_exact(&self, token_in: Address, amount_out: U256) -> Option<U256> {
+        let (reserve_in, reserve_out) = if token_in == self.token0 {
+            (self.reserve0, self.reserve1)
+        } else {
+            (self.reserve1, self.reserve0)
+        };
+        if amount_out.is_zero() {
+            return Some(U256::zero());
+        }
+        if amount_out >= reserve_out {
+            return None;
+        }
+
+        let numerator = reserve_in * amount_out * U256::from(10000);
+        let denominator = (reserve_out - amount_out) * (U256::from(10000) - self.router_fee);
+        Some(numerator / denominator + U256::one())
+    }
+}
+
+/// Constant-sum pool `price * x + y = k`: trades at a fixed rate until the
+/// output side runs dry. Setting `max_out` makes it a capped constant-sum pool,
+/// where a single trade can never take out more than the cap either.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConstantSumPool {
+    pub address: Address,
+
+    pub token0: Address,
+    pub token1: Address,
+
+    pub reserve0: U256,
+    pub reserve1: U256,
+
+    // router fee
+    pub router_fee: U256,
+    // token1 paid per token0, 1.0 for a pegged pair
+    #[serde(default = "default_price")]
+    pub price: f64,
+    // per-trade output cap, in units of the token going out
+    #[serde(default)]
+    pub max_out: Option<U256>,
+}
+
+fn default_price() -> f64 {
+    1.0
+}
+
+impl ConstantSumPool {
+    pub fn fee(&self) -> f64 {
+        1.0 - (self.router_fee.as_u64() as f64 / 10000.0)
+    }
+
+    /// Most the pool can pay out of `token_out` in one trade.
+    pub fn available_out(&self, token_out: Address) -> f64 {
+        let reserve = if token_out == self.token0 {
+            self.reserve0.as_u128() as f64
+        } else {
+            self.reserve1.as_u128() as f64
+        };
+        match self.max_out {
+            Some(cap) => reserve.min(cap.as_u128() as f64),
+            None => reserve,
+        }
+    }
+
+    /// Largest input of `token_in` the pool can fill in one trade.
+    pub fn max_amount_in(&self, token_in: Address) -> f64 {
+        let (rate, token_out) = if token_in == self.token0 {
+            (self.price, self.token1)
+        } else {
+            (1.0 / self.price, self.token0)
+        };
+
+        self.available_out(token_out) / (self.fee() * rate)
+    }
+
+    /// Largest whole-unit input of `token_in` that `get_amount_out_exact`
+    /// fills: the fee is rounded down there, so this can be a unit or more
+    /// past the float `max_amount_in`.
+    pub fn max_amount_in_exact(&self, token_in: Address) -> U256 {
+        let (rate, token_out) = if token_in == self.token0 {
+            (self.price, self.token1)
+        } else {
+            (1.0 / self.price, self.token0)
+        };
+        let available = floor_units(self.available_out(token_out));
+        let fee = U256::from(10000) - self.router_fee;
+        if fee.is_zero() {
+            return U256::from(u128::MAX);
+        }
+
+        // largest amount after the fee whose output stays within `available`
+        let max_after_fee = if rate == 1.0 {
+            available
+        } else {
+            let out = |after_fee: U256| floor_units(after_fee.as_u128() as f64 * rate);
+            let mut after_fee = floor_units((available.as_u128() as f64 + 1.0) / rate);
+            while !after_fee.is_zero() && out(after_fee) > available {
+                after_fee -= U256::one();
+            }
+            while out(after_fee + U256::one()) <= available {
+                after_fee += U256::one();
+            }
+            after_fee
+        };
+        // amount_in * fee / 10000, rounded down, is at most `max_after_fee`
+        ((max_after_fee + U256::one()) * U256::from(10000) - U256::one()) / fee
+    }
+
+    /// Output for `amount_in`; `None` when it is more than the pool can pay
+    /// out, where the trade reverts rather than paying the capped amount.
+    pub fn get_amount_out(&self, token_in: Address, amount_in: f64) -> Option<f64> {
+        let (rate, token_out) = if token_in == self.token0 {
+            (self.price, self.token1)
+        } else {
+            (1.0 / self.price, self.token0)
+        };
+        let amount_out = amount_in * self.fee() * rate;
+        let available = self.available_out(token_out);
+        // float round-off at the boundary still fills
+        if amount_out > available * (1.0 + 1e-12) {
+            return None;
+        }
+
+        Some(amount_out.min(available))
+    }
+
+    /// Input of `token_in` that buys `amount_out`; infinite past what the
+    /// pool can pay out.
+    pub fn get_amount_in(&self, token_in: Address, amount_out: f64) -> f64 {
+        let (rate, token_out) = if token_in == self.token0 {
+            (self.price, self.token1)
+        } else {
+            (1.0 / self.price, self.token0)
+        };
+        if amount_out > self.available_out(token_out) {
+            return f64::INFINITY;
+        }
+
+        amount_out / (self.fee() * rate)
+    }
+
+    /// Integer output, fee taken in basis points and the result rounded down;
+    /// `None` past what the pool can pay out.
+    pub fn get_amount_out_exact(&self, token_in: Address, amount_in: U256) -> Option<U256> {
+        let (rate, token_out) = if token_in == self.token0 {
+            (self.price, self.token1)
+        } else {
+            (1.0 / self.price, self.token0)
+        };
+        let after_fee = amount_in * (U256::from(10000) - self.router_fee) / U256::from(10000);
+        let amount_out = if rate == 1.0 {
+            after_fee
+        } else {
+            floor_units(after_fee.as_u128() as f64 * rate)
+        };
+
+        if amount_out > floor_units(self.available_out(token_out)) {
+            return None;
+        }
+        Some(amount_out)
+    }
+
+    pub fn trading_set(&self, tolerance: f64) -> Vec<([f64; 4], f64)> {
+        constant_sum_trading_set(
+            [self.available_out(self.token0), self.available_out(self.token1)],
+            self.fee(),
+            self.price,
+            tolerance,
+        )
+    }
+}
+
+/// Linear trading set of a two-token constant-sum pool in the router's
+/// `[lambda0, delta0, lambda1, delta1]` variables (lambda received from the
+/// pool, delta tendered to it). Each entry `(a, b)` reads `a . x <= b`.
+///
+/// `available` is the most that can leave the pool per token (the reserve, or
+/// the cap for a capped pool), so the output never exceeds what the pool holds.
+/// `tolerance` lets the invariant drop by that fraction of `price * R0 + R1`;
+/// see `SolverConfig::tolerance`.
+pub fn constant_sum_trading_set(
+    available: [f64; 2],
+    fee: f64,
+    price: f64,
+    tolerance: f64,
+) -> Vec<([f64; 4], f64)> {
+    vec![
+        // price * (R0 + fee * delta0 - lambda0) + (R1 + fee * delta1 - lambda1)
+        //     >= (1 - tolerance) * (price * R0 + R1)
+        (
+            [price, -fee * price, 1.0, -fee],
+            tolerance * (price * available[0] + available[1]),
+        ),
+        // lambda0 <= R0
+        ([1.0, 0.0, 0.0, 0.0], available[0]),
+        // lambda1 <= R1
+        ([0.0, 0.0, 1.0, 0.0], available[1]),
+    ]
+}
+
+/// Weighted geometric-mean pool (Balancer): `prod(R_j ^ w_j)` is kept constant,
+/// with the weights summing to one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BalancerPool {
+    pub address: Address,
+
+    pub tokens: Vec<Address>,
+    pub reserves: Vec<U256>,
+    pub weights: Vec<f64>,
+
+    // router fee
+    pub router_fee: U256,
+}
+
+impl BalancerPool {
+    pub fn fee(&self) -> f64 {
+        1.0 - (self.router_fee.as_u64() as f64 / 10000.0)
+    }
+
+    fn index_of(&self, token: Address) -> usize {
+        self.tokens.iter().position(|&t| t == token).unwrap()
+    }
+
+    pub fn get_amount_out(&self, token_in: Address, token_out: Address, amount_in: f64) -> f64 {
+        let i = self.index_of(token_in);
+        let o = self.index_of(token_out);
+        let reserve_in = self.reserves[i].as_u128() as f64;
+        let reserve_out = self.reserves[o].as_u128() as f64;
+
+        let ratio = reserve_in / (reserve_in + amount_in * self.fee());
+        reserve_out * (1.0 - ratio.powf(self.weights[i] / self.weights[o]))
+    }
+
+    /// Input of `token_in` that buys `amount_out` of `token_out`; infinite
+    /// when the pool does not hold that much.
+    pub fn get_amount_in(&self, token_in: Address, token_out: Address, amount_out: f64) -> f64 {
+        let i = self.index_of(token_in);
+        let o = self.index_of(token_out);
+        let reserve_in = self.reserves[i].as_u128() as f64;
+        let reserve_out = self.reserves[o].as_u128() as f64;
+        if amount_out >= reserve_out {
+            return f64::INFINITY;
+        }
+
+        let ratio = reserve_out / (reserve_out - amount_out);
+        reserve_in * (ratio.powf(self.weights[o] / self.weights[i]) - 1.0) / self.fee()
+    }
+
+    pub fn spot_price(&self, token_in: Address, token_out: Address) -> f64 {
+        let i = self.index_of(token_in);
+        let o = self.index_of(token_out);
+        let reserve_in = self.reserves[i].as_u128() as f64;
+        let reserve_out = self.reserves[o].as_u128() as f64;
+
+        (reserve_out / self.weights[o]) / (reserve_in / self.weights[i]) * self.fee()
+    }
+}
+
+/// Any pool the graph search and the router know how to trade through.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind")]
+pub enum Cfmm {
+    UniV2(UniV2Pool),
+    ConstantSum(ConstantSumPool),
+    OrderBook(OrderBook),
+    Balancer(BalancerPool),
+}
+
+impl Cfmm {
+    pub fn address(&self) -> Address {
+        match self {
+            Cfmm::UniV2(pool) => pool.address,
+            Cfmm::ConstantSum(pool) => pool.address,
+            Cfmm::OrderBook(book) => book.address,
+            Cfmm::Balancer(pool) => pool.address,
+        }
+    }
+
+    pub fn tokens(&self) -> Vec<Address> {
+        match self {
+            Cfmm::Balancer(pool) => pool.tokens.clone(),
+            _ => vec![self.token0(), self.token1()],
+        }
+    }
+
+    pub fn token0(&self) -> Address {
+        match self {
+            Cfmm::UniV2(pool) => pool.token0,
+            Cfmm::ConstantSum(pool) => pool.token0,
+            Cfmm::OrderBook(book) => book.base,
+            Cfmm::Balancer(pool) => pool.tokens[0],
+        }
+    }
+
+    pub fn token1(&self) -> Address {
+        match self {
+            Cfmm::UniV2(pool) => pool.token1,
+            Cfmm::ConstantSum(pool) => pool.token1,
+            Cfmm::OrderBook(book) => book.quote,
+            Cfmm::Balancer(pool) => pool.tokens[1],
+        }
+    }
+
+    pub fn reserves(&self) -> (U256, U256) {
+        match self {
+            Cfmm::UniV2(pool) => (pool.reserve0, pool.reserve1),
+            Cfmm::ConstantSum(pool) => (pool.reserve0, pool.reserve1),
+            // what a taker can pull out of each side of the book
+            Cfmm::OrderBook(book) => (
+                U256::from(book.ask_depth() as u128),
+                U256::from(book.bid_depth() as u128),
+            ),
+            Cfmm::Balancer(pool) => (pool.reserves[0], pool.reserves[1]),
+        }
+    }
+
+    pub fn router_fee(&self) -> U256 {
+        match self {
+            Cfmm::UniV2(pool) => pool.router_fee,
+            Cfmm::ConstantSum(pool) => pool.router_fee,
+            Cfmm::OrderBook(book) => book.router_fee,
+            Cfmm::Balancer(pool) => pool.router_fee,
+        }
+    }
+
+    pub fn other_token(&self, token: Address) -> Address {
+        if token == self.token0() {
+            self.token1()
+        } else {
+            self.token0()
+        }
+    }
+
+    pub fn get_amount_out(&self, token_in: Address, amount_in: f64) -> Option<f64> {
+        self.swap(token_in, self.other_token(token_in), amount_in)
+    }
+
+    /// Largest input of `token_in` the pool can fill in one trade for
+    /// `token_out`; infinite for the curves that never run dry.
+    pub fn max_amount_in(&self, token_in: Address, _token_out: Address) -> f64 {
+        match self {
+            Cfmm::ConstantSum(pool) => pool.max_amount_in(token_in),
+            Cfmm::OrderBook(book) => book.max_amount_in(token_in),
+            _ => f64::INFINITY,
+        }
+    }
+
+    /// Largest whole-unit input of `token_in` that `swap_exact` fills for
+    /// `token_out`, from the same integer math; unbounded for the curves that
+    /// never run dry.
+    pub fn max_amount_in_exact(&self, token_in: Address, _token_out: Address) -> U256 {
+        match self {
+            Cfmm::ConstantSum(pool) => pool.max_amount_in_exact(token_in),
+            Cfmm::OrderBook(book) => floor_units(book.max_amount_in(token_in)),
+            _ => U256::from(u128::MAX),
+        }
+    }
+
+    /// Output of `token_out` for `amount_in` of `token_in`; only multi-token
+    /// pools care about `token_out`, pairs always pay out the other side.
+    /// `None` when the input is more than the pool can fill
+    /// (`max_amount_in`).
+    pub fn swap(&self, token_in: Address, token_out: Address, amount_in: f64) -> Option<f64> {
+        match self {
+            Cfmm::UniV2(pool) => Some(pool.get_amount_out(token_in, amount_in)),
+            Cfmm::ConstantSum(pool) => pool.get_amount_out(token_in, amount_in),
+            Cfmm::OrderBook(book) => book.get_amount_out(token_in, amount_in),
+            Cfmm::Balancer(pool) => Some(pool.get_amount_out(token_in, token_out, amount_in)),
+        }
+    }
+
+    /// `swap` in token units: integer math where the pool contract has it
+    /// (UniV2, constant sum), otherwise the float output rounded down.
+    pub fn swap_exact(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        amount_in: U256,
+    ) -> Option<U256> {
+        match self {
+            Cfmm::UniV2(pool) => Some(pool.get_amount_out_exact(token_in, amount_in)),
+            Cfmm::ConstantSum(pool) => pool.get_amount_out_exact(token_in, amount_in),
+            _ => self
+                .swap(token_in, token_out, amount_in.as_u128() as f64)
+                .map(floor_units),
+        }
+    }
+
+    /// Input of `token_in` that buys `amount_out` of `token_out`, the inverse
+    /// of `swap`; infinite when the pool cannot pay that much.
+    pub fn swap_in(&self, token_in: Address, token_out: Address, amount_out: f64) -> f64 {
+        match self {
+            Cfmm::UniV2(pool) => pool.get_amount_in(token_in, amount_out),
+            Cfmm::ConstantSum(pool) => pool.get_amount_in(token_in, amount_out),
+            Cfmm::OrderBook(book) => book.get_amount_in(token_in, amount_out),
+            Cfmm::Balancer(pool) => pool.get_amount_in(token_in, token_out, amount_out),
+        }
+    }
+
+    /// Whole-unit input whose `swap_exact` pays at least `amount_out`:
+    /// `getAmountIn` for UniV2, otherwise the least such input, bisected
+    /// from the `swap_in` estimate. `None` when no input pays that much.
+    pub fn swap_in_exact(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        amount_out: U256,
+    ) -> Option<U256> {
+        if let Cfmm::UniV2(pool) = self {
+            return pool.get_amount_in_exact(token_in, amount_out);
+        }
+        if amount_out.is_zero() {
+            return Some(U256::zero());
+        }
+        let estimate = self.swap_in(token_in, token_out, amount_out.as_u128() as f64);
+        if !estimate.is_finite() {
+            return None;
+        }
+
+        // bracket the answer below the pool's exact limit, then bisect on
+        // the monotone integer output
+        let pays = |amount_in: U256| {
+            self.swap_exact(token_in, token_out, amount_in)
+                .is_some_and(|out| out >= amount_out)
+        };
+        let cap = self.max_amount_in_exact(token_in, token_out);
+        let mut hi = (floor_units(estimate) + U256::one()).min(cap);
+        let mut doublings = 0;
+        while !pays(hi) {
+            if doublings == 32 || hi == cap {
+                return None;
+            }
+            hi = (hi * U256::from(2)).min(cap);
+            doublings += 1;
+        }
+        let mut lo = U256::zero();
+        while hi - lo > U256::one() {
+            let mid = (lo + hi) / 2;
+            if pays(mid) {
+                hi = mid;
+            } else {
+                lo = mid;
+            }
+        }
+        Some(hi)
+    }
+
+    /// Moves the pool to its state after `amount_in` of `token_in` bought
+    /// `amount_out` of `token_out`; the fee stays in the pool.
+    pub fn apply_swap(
+        &mut self,
+        token_in: Address,
+        token_out: Address,
+        amount_in: U256,
+        amount_out: U256,
+    ) {
+        match self {
+            Cfmm::UniV2(pool) => {
+                if token_in == pool.token0 {
+                    pool.reserve0 += amount_in;
+                    pool.reserve1 = pool.reserve1.saturating_sub(amount_out);
+                } else {
+                    pool.reserve1 += amount_in;
+                    pool.reserve0 = pool.reserve0.saturating_sub(amount_out);
+                }
+            }
+            Cfmm::ConstantSum(pool) => {
+                if token_in == pool.token0 {
+                    pool.reserve0 += amount_in;
+                    pool.reserve1 = pool.reserve1.saturating_sub(amount_out);
+                } else {
+                    pool.reserve1 += amount_in;
+                    pool.reserve0 = pool.reserve0.saturating_sub(amount_out);
+                }
+            }
+            Cfmm::OrderBook(book) => book.fill(token_in, amount_in.as_u128() as f64),
+            Cfmm::Balancer(pool) => {
+                let i = pool.index_of(token_in);
+                let o = pool.index_of(token_out);
+                pool.reserves[i] += amount_in;
+                pool.reserves[o] = pool.reserves[o].saturating_sub(amount_out);
+            }
+        }
+    }
+
+    /// The pool after its state moved against a `token_in` -> `token_out`
+    /// trade by `shift` (a fraction): the input reserve grows and the output
+    /// reserve shrinks by it, and book quotes worsen and thin out by it.
+    pub fn shifted_against(&self, token_in: Address, token_out: Address, shift: f64) -> Cfmm {
+        let scale = |amount: U256, factor: f64| floor_units(amount.as_u128() as f64 * factor);
+        let (up, down) = (1.0 + shift, 1.0 - shift);
+        let mut pool = self.clone();
+        match &mut pool {
+            Cfmm::UniV2(UniV2Pool {
+                token0,
+                reserve0,
+                reserve1,
+                ..
+            })
+            | Cfmm::ConstantSum(ConstantSumPool {
+                token0,
+                reserve0,
+                reserve1,
+                ..
+            }) => {
+                let (reserve_in, reserve_out) = if token_in == *token0 {
+                    (reserve0, reserve1)
+                } else {
+                    (reserve1, reserve0)
+                };
+                *reserve_in = scale(*reserve_in, up);
+                *reserve_out = scale(*reserve_out, down);
+            }
+            Cfmm::OrderBook(book) => {
+                for level in &mut book.bids {
+                    level.price *= down;
+                    level.size *= down;
+                }
+                for level in &mut book.asks {
+                    level.price *= up;
+                    level.size *= down;
+                }
+            }
+            Cfmm::Balancer(pool) => {
+                let i = pool.index_of(token_in);
+                let o = pool.index_of(token_out);
+                pool.reserves[i] = scale(pool.reserves[i], up);
+                pool.reserves[o] = scale(pool.reserves[o], down);
+            }
+        }
+        pool
+    }
+
+    /// The pool with each token's liquidity multiplied by `factor(token)`:
+    /// reserves for the AMMs, the sizes of the levels paying the token out
+    /// for a book.
+    pub fn scale_reserves<F: Fn(Address) -> f64>(&self, factor: F) -> Cfmm {
+        let scale =
+            |amount: U256, token: Address| floor_units(amount.as_u128() as f64 * factor(token));
+        let mut pool = self.clone();
+        match &mut pool {
+            Cfmm::UniV2(pool) => {
+                pool.reserve0 = scale(pool.reserve0, pool.token0);
+                pool.reserve1 = scale(pool.reserve1, pool.token1);
+            }
+            Cfmm::ConstantSum(pool) => {
+                pool.reserve0 = scale(pool.reserve0, pool.token0);
+                pool.reserve1 = scale(pool.reserve1, pool.token1);
+            }
+            Cfmm::OrderBook(book) => {
+                // bids pay out quote, asks pay out base
+                for level in &mut book.bids {
+                    level.size *= factor(book.quote);
+                }
+                for level in &mut book.asks {
+                    level.size *= factor(book.base);
+                }
+            }
+            Cfmm::Balancer(pool) => {
+                for (reserve, token) in pool.reserves.iter_mut().zip(&pool.tokens) {
+                    *reserve = scale(*reserve, *token);
+                }
+            }
+        }
+        pool
+    }
+
+    /// The pool after a router trade: `tendered[k]` of `tokens()[k]` paid in
+    /// and `received[k]` taken out, fees staying in the reserves. Books lose
+    /// the levels the inputs walk through.
+    pub fn after_trade(&self, received: &[f64], tendered: &[f64]) -> Cfmm {
+        let update = |reserve: U256, k: usize| {
+            floor_units(reserve.as_u128() as f64 + tendered[k] - received[k])
+        };
+        let mut pool = self.clone();
+        match &mut pool {
+            Cfmm::UniV2(UniV2Pool {
+                reserve0, reserve1, ..
+            })
+            | Cfmm::ConstantSum(ConstantSumPool {
+                reserve0, reserve1, ..
+            }) => {
+                *reserve0 = update(*reserve0, 0);
+                *reserve1 = update(*reserve1, 1);
+            }
+            Cfmm::OrderBook(book) => {
+                let tokens = [book.base, book.quote];
+                for (token, amount) in tokens.iter().zip(tendered) {
+                    book.fill(*token, *amount);
+                }
+            }
+            Cfmm::Balancer(pool) => {
+                for (k, reserve) in pool.reserves.iter_mut().enumerate() {
+                    *reserve = update(*reserve, k);
+                }
+            }
+        }
+        pool
+    }
+
+    pub fn spot_price(&self, token_in: Address) -> f64 {
+        self.spot_price_to(token_in, self.other_token(token_in))
+    }
+
+    /// Fee-adjusted marginal rate for an infinitesimal trade of `token_in`
+    /// into `token_out`.
+    pub fn spot_price_to(&self, token_in: Address, token_out: Address) -> f64 {
+        match self {
+            Cfmm::UniV2(pool) => {
+                let (reserve_in, reserve_out) = if token_in == pool.token0 {
+                    (pool.reserve0, pool.reserve1)
+                } else {
+                    (pool.reserve1, pool.reserve0)
+                };
+                (reserve_out.as_u128() as f64 / reserve_in.as_u128() as f64) * pool.fee()
+            }
+            Cfmm::ConstantSum(pool) => {
+                let rate = if token_in == pool.token0 {
+                    pool.price
+                } else {
+                    1.0 / pool.price
+                };
+                rate * pool.fee()
+            }
+            Cfmm::OrderBook(book) => {
+                let rate = if token_in == book.base {
+                    book.bids.first().map_or(0.0, |level| level.price)
+                } else {
+                    book.asks.first().map_or(0.0, |level| 1.0 / level.price)
+                };
+                rate * book.fee()
+            }
+            Cfmm::Balancer(pool) => pool.spot_price(token_in, token_out),
+        }
+    }
+}
+
+impl From<UniV2Pool> for Cfmm {
+    fn from(pool: UniV2Pool) -> Self {
+        Cfmm::UniV2(pool)
+    }
+}
+
+impl From<ConstantSumPool> for Cfmm {
+    fn from(pool: ConstantSumPool) -> Self {
+        Cfmm::ConstantSum(pool)
+    }
+}
+
+impl From<OrderBook> for Cfmm {
+    fn from(book: OrderBook) -> Self {
+        Cfmm::OrderBook(book)
+    }
+}
+
+impl From<BalancerPool> for Cfmm {
+    fn from(pool: BalancerPool) -> Self {
+        Cfmm::Balancer(pool)
+    }
+}
+
+/// Whole token units in `amount`, rounded down; negative amounts are zero.
+pub fn floor_units(amount: f64) -> U256 {
+    U256::from(amount.max(0.0).floor() as u128)
+}
+
+/// Token a closed cycle of pools starts and ends in, i.e. the one shared by
+/// the first and the last pool.
+pub fn cycle_start_token(pools: &[Cfmm]) -> Option<Address> {
+    let first = pools.first()?;
+    let last = pools.last()?;
+    let last_tokens = last.tokens();
+    first
+        .tokens()
+        .into_iter()
+        .find(|token| last_tokens.contains(token))
+}
+
+/// Tokens visited when walking `pools` from `token_in`: each hop pays out the
+/// token it shares with the next pool, the last hop the other side of the pair
+/// (or `token_in` again if the last pool holds it, closing a cycle).
+pub fn path_tokens(pools: &[Cfmm], token_in: Address) -> Vec<Address> {
+    let mut tokens = vec![token_in];
+    let mut token = token_in;
+
+    for (k, pool) in pools.iter().enumerate() {
+        let candidates = pool.tokens();
+        let next = match pools.get(k + 1) {
+            Some(next_pool) => {
+                let next_tokens = next_pool.tokens();
+                candidates
+                    .into_iter()
+                    .find(|t| *t != token && next_tokens.contains(t))
+            }
+            None if k > 0 && candidates.contains(&token_in) && token != token_in => Some(token_in),
+            None => None,
+        };
+        token = next.unwrap_or_else(|| pool.other_token(token));
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+/// Pushes `amount_in` of `token_in` through `pools` in order and returns the
+/// amount that comes out of the last one; `None` when a pool cannot fill
+/// what reaches it.
+pub fn simulate_path(pools: &[Cfmm], token_in: Address, amount_in: f64) -> Option<f64> {
+    let tokens = path_tokens(pools, token_in);
+    let mut amount = amount_in;
+
+    for (k, pool) in pools.iter().enumerate() {
+        amount = pool.swap(tokens[k], tokens[k + 1], amount)?;
+    }
+
+    Some(amount)
+}
+
+/// Input of `token_in` that makes `pools`, in order, pay `amount_out` of
+/// the path's last token: `simulate_path` run backwards. Infinite when a
+/// pool cannot pay what the next one needs.
+pub fn path_amount_in(pools: &[Cfmm], token_in: Address, amount_out: f64) -> f64 {
+    let tokens = path_tokens(pools, token_in);
+    let mut amount = amount_out;
+
+    for (k, pool) in pools.iter().enumerate().rev() {
+        amount = pool.swap_in(tokens[k], tokens[k + 1], amount);
+    }
+
+    amount
+}
+
+/// Whole-unit amounts along the path for an exact output, like the router
+/// library's `getAmountsIn`: entry `k` goes into pool `k`, the last entry
+/// is `amount_out`. Each pool is taken at its current state.
+pub fn path_amounts_in(pools: &[Cfmm], token_in: Address, amount_out: U256) -> Option<Vec<U256>> {
+    let tokens = path_tokens(pools, token_in);
+    let mut amounts = vec![amount_out];
+
+    for (k, pool) in pools.iter().enumerate().rev() {
+        let amount_in = pool.swap_in_exact(tokens[k], tokens[k + 1], amounts[0])?;
+        amounts.insert(0, amount_in);
+    }
+
+    Some(amounts)
+}
+
+/// Input in `[0, max_input]` that maximises `simulate_path(..) - input`,
+/// found by golden-section search; the output curve is concave, so the
+/// profit has a single peak. Inputs the path cannot fill count as the
+/// worst, which keeps the search below the path's limit.
+pub fn best_input(pools: &[Cfmm], token_in: Address, max_input: f64) -> f64 {
+    golden_section_max(
+        |x: f64| simulate_path(pools, token_in, x).map_or(f64::NEG_INFINITY, |out| out - x),
+        max_input,
+    )
+}
+
+/// Point in `[0, max]` maximising `f` by golden-section search: the peak
+/// when `f` has a single one, otherwise a local maximum.
+pub fn golden_section_max(f: impl Fn(f64) -> f64, max: f64) -> f64 {
+    let ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let (mut lo, mut hi) = (0.0, max.max(0.0));
+    for _ in 0..100 {
+        if hi - lo <= 1e-9 * hi.max(1.0) {
+            break;
+        }
+        let a = hi - ratio * (hi - lo);
+        let b = lo + ratio * (hi - lo);
+        if f(a) < f(b) {
+            lo = a;
+        } else {
+            hi = b;
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+/// Executes the path in token units on `pools`, hop by hop with `swap_exact`
+/// and `apply_swap`, and returns the final output; `None`, with the hops
+/// before it applied, when a pool cannot fill what reaches it.
+pub fn execute_path(pools: &mut [Cfmm], token_in: Address, amount_in: U256) -> Option<U256> {
+    let tokens = path_tokens(pools, token_in);
+    let mut amount = amount_in;
+
+    for (k, pool) in pools.iter_mut().enumerate() {
+        let amount_out = pool.swap_exact(tokens[k], tokens[k + 1], amount)?;
+        pool.apply_swap(tokens[k], tokens[k + 1], amount, amount_out);
+        amount = amount_out;
+    }
+
+    Some(amount)
+}
+
+/// Exact output of the path at `points + 1` inputs from 0 to `max_input`,
+/// spaced quadratically so they are densest near zero, where the output
+/// curve bends most. Inputs the path cannot fill are left out, so the last
+/// point is the furthest the path can take.
+pub fn path_curve(
+    pools: &[Cfmm],
+    token_in: Address,
+    max_input: f64,
+    points: usize,
+) -> Vec<(f64, f64)> {
+    (0..=points)
+        .filter_map(|k| {
+            let share = k as f64 / points.max(1) as f64;
+            let input = max_input * share * share;
+            simulate_path(pools, token_in, input).map(|output| (input, output))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::order_book::PriceLevel;
+
+    fn token(i: u8) -> Address {
+        Address::from([i; 20])
+    }
+
+    fn uni_v2() -> Cfmm {
+        UniV2Pool::fixture(0x10, token(0), token(1), (1_000_000, 2_000_000))
+    }
+
+    fn constant_sum(price: f64, max_out: Option<u64>) -> Cfmm {
+        Cfmm::ConstantSum(ConstantSumPool {
+            address: Address::from([0x11; 20]),
+            token0: token(0),
+            token1: token(1),
+            reserve0: U256::from(10_000),
+            reserve1: U256::from(10_000),
+            router_fee: U256::from(10),
+            price,
+            max_out: max_out.map(U256::from),
+        })
+    }
+
+    fn order_book() -> Cfmm {
+        let level = |price, size| PriceLevel { price, size };
+        let mut book = OrderBook {
+            address: Address::from([0x12; 20]),
+            base: token(0),
+            quote: token(1),
+            bids: vec![level(2.0, 1000.0), level(1.9, 2000.0)],
+            asks: vec![level(2.1, 1000.0), level(2.2, 2000.0)],
+            router_fee: U256::from(5),
+        };
+        book.sort_levels();
+        Cfmm::OrderBook(book)
+    }
+
+    fn balancer() -> Cfmm {
+        Cfmm::Balancer(BalancerPool {
+            address: Address::from([0x13; 20]),
+            tokens: vec![token(0), token(1), token(2)],
+            reserves: vec![
+                U256::from(1_000_000),
+                U256::from(500_000),
+                U256::from(2_000_000),
+            ],
+            weights: vec![0.5, 0.25, 0.25],
+            router_fee: U256::from(30),
+        })
+    }
+
+    fn pays(
+        pool: &Cfmm,
+        token_in: Address,
+        token_out: Address,
+        amount_in: U256,
+        amount_out: U256,
+    ) -> bool {
+        pool.swap_exact(token_in, token_out, amount_in)
+            .is_some_and(|out| out >= amount_out)
+    }
+
+    // `swap_in_exact` buys at least `amount_out`, and `slack + 1` units less
+    // input does not; returns the input.
+    fn assert_round_trip(
+        pool: &Cfmm,
+        token_in: Address,
+        token_out: Address,
+        amount_out: u64,
+        slack: u64,
+    ) -> U256 {
+        let amount_out = U256::from(amount_out);
+        let amount_in = pool.swap_in_exact(token_in, token_out, amount_out).unwrap();
+        assert!(pays(pool, token_in, token_out, amount_in, amount_out));
+        if let Some(less) = amount_in.checked_sub(U256::from(slack + 1)) {
+            assert!(!pays(pool, token_in, token_out, less, amount_out));
+        }
+        amount_in
+    }
+
+    #[test]
+    fn univ2_round_trip() {
+        let pool = uni_v2();
+        for (token_in, token_out) in [(token(0), token(1)), (token(1), token(0))] {
+            for amount_out in [1, 7, 1000, 123_456, 999_000] {
+                // getAmountIn rounds up even on an exact division, so it may
+                // be one unit more than needed, never two
+                let amount_in = assert_round_trip(&pool, token_in, token_out, amount_out, 1);
+                let (reserve_in, reserve_out) = if token_in == token(0) {
+                    (1_000_000u128, 2_000_000u128)
+                } else {
+                    (2_000_000, 1_000_000)
+                };
+                let numerator = reserve_in * amount_out as u128 * 10000;
+                let denominator = (reserve_out - amount_out as u128) * 9970;
+                let one_less = amount_in - U256::one();
+                assert_eq!(
+                    pays(&pool, token_in, token_out, one_less, U256::from(amount_out)),
+                    numerator.is_multiple_of(denominator)
+                );
+            }
+        }
+        assert_eq!(
+            pool.swap_in_exact(token(0), token(1), U256::from(2_000_000)),
+            None
+        );
+    }
+
+    #[test]
+    fn constant_sum_round_trip() {
+        for pool in [
+            constant_sum(1.0, None),
+            constant_sum(2.0, None),
+            constant_sum(1.0, Some(500)),
+        ] {
+            for (token_in, token_out) in [(token(0), token(1)), (token(1), token(0))] {
+                for amount_out in [1, 7, 333, 500] {
+                    assert_round_trip(&pool, token_in, token_out, amount_out, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn constant_sum_fails_past_capacity() {
+        let pool = constant_sum(1.0, Some(500));
+        assert_eq!(
+            pool.swap_in_exact(token(0), token(1), U256::from(501)),
+            None
+        );
+        let max_in = pool.max_amount_in_exact(token(0), token(1));
+        // the fee rounds down, so the exact limit is past the float one
+        assert!(max_in > floor_units(pool.max_amount_in(token(0), token(1))));
+        assert!(pool.swap_exact(token(0), token(1), max_in).is_some());
+        assert_eq!(
+            pool.swap_exact(token(0), token(1), max_in + U256::one()),
+            None
+        );
+        assert_eq!(pool.swap(token(0), token(1), 10_000.0), None);
+
+        for pool in [constant_sum(2.0, None), constant_sum(0.3, Some(777))] {
+            for (token_in, token_out) in [(token(0), token(1)), (token(1), token(0))] {
+                let max_in = pool.max_amount_in_exact(token_in, token_out);
+                assert!(pool.swap_exact(token_in, token_out, max_in).is_some());
+                assert_eq!(
+                    pool.swap_exact(token_in, token_out, max_in + U256::one()),
+                    None
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn order_book_round_trip() {
+        let pool = order_book();
+        // base in walks the bids, quote in walks the asks
+        for amount_out in [1, 7, 1999, 2001, 5000] {
+            assert_round_trip(&pool, token(0), token(1), amount_out, 0);
+        }
+        for amount_out in [1, 7, 999, 1001, 2500] {
+            assert_round_trip(&pool, token(1), token(0), amount_out, 0);
+        }
+    }
+
+    #[test]
+    fn order_book_fails_past_depth() {
+        let pool = order_book();
+        assert_eq!(
+            pool.swap_in_exact(token(1), token(0), U256::from(3001)),
+            None
+        );
+        assert_eq!(pool.swap(token(0), token(1), 3000.5), None);
+        assert!(pool.swap(token(0), token(1), 3000.0).is_some());
+    }
+
+    #[test]
+    fn balancer_round_trip() {
+        let pool = balancer();
+        for (token_in, token_out) in [
+            (token(0), token(1)),
+            (token(2), token(0)),
+            (token(1), token(2)),
+        ] {
+            for amount_out in [1, 7, 1000, 123_456] {
+                assert_round_trip(&pool, token_in, token_out, amount_out, 0);
+            }
+        }
+    }
+
+    #[test]
+    fn path_amounts_in_buy_the_output() {
+        let mut pools = vec![uni_v2(), constant_sum(0.5, None)];
+        let amount_out = U256::from(4000);
+        let amounts = path_amounts_in(&pools, token(0), amount_out).unwrap();
+        assert_eq!(amounts.len(), 3);
+        assert_eq!(amounts[2], amount_out);
+        let out = execute_path(&mut pools, token(0), amounts[0]).unwrap();
+        assert!(out >= amount_out);
+    }
+}
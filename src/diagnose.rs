@@ -0,0 +1,87 @@
+use crate::solver::{solve_outcome, ConicProblem, SolveOutcome, SolverConfig, SolverError};
+
+/// Explains an infeasible problem by a minimal set of its labelled
+/// constraint groups that is infeasible on its own: drop any one of them and
+/// the rest can be satisfied. Blocks sharing a label form one group, an
+/// unlabelled block is named by its first row.
+///
+/// Returns `None` when the problem is feasible. Runs one feasibility solve
+/// per group (deletion filter), so it is meant for failures, not hot paths.
+pub fn explain_infeasibility(
+    problem: &ConicProblem,
+    config: &SolverConfig,
+) -> Result<Option<Vec<String>>, SolverError> {
+    let names = block_names(problem);
+    let mut groups: Vec<String> = Vec::new();
+    for name in &names {
+        if !groups.contains(name) {
+            groups.push(name.clone());
+        }
+    }
+
+    let mut keep = vec![true; groups.len()];
+    if !infeasible(problem, &names, &groups, &keep, config)? {
+        return Ok(None);
+    }
+
+    for g in 0..groups.len() {
+        keep[g] = false;
+        if !infeasible(problem, &names, &groups, &keep, config)? {
+            // needed for the conflict
+            keep[g] = true;
+        }
+    }
+
+    Ok(Some(
+        groups
+            .into_iter()
+            .zip(keep)
+            .filter(|(_, kept)| *kept)
+            .map(|(group, _)| group)
+            .collect(),
+    ))
+}
+
+fn block_names(problem: &ConicProblem) -> Vec<String> {
+    let mut names = Vec::with_capacity(problem.cones.len());
+    let mut row = 0;
+    for (cone, label) in problem.cones.iter().zip(&problem.labels) {
+        if label.is_empty() {
+            names.push(format!("row {}", row));
+        } else {
+            names.push(label.clone());
+        }
+        row += cone.dim();
+    }
+    names
+}
+
+// Feasibility of the blocks whose group is kept, objective dropped.
+fn infeasible(
+    problem: &ConicProblem,
+    names: &[String],
+    groups: &[String],
+    keep: &[bool],
+    config: &SolverConfig,
+) -> Result<bool, SolverError> {
+    let mut sub = ConicProblem::new(problem.n_vars);
+    let mut row = 0;
+    for (k, cone) in problem.cones.iter().enumerate() {
+        let dim = cone.dim();
+        let g = groups.iter().position(|group| *group == names[k]).unwrap();
+        if keep[g] {
+            sub.add_block(
+                *cone,
+                problem.a[row..row + dim].to_vec(),
+                problem.b[row..row + dim].to_vec(),
+            );
+        }
+        row += dim;
+    }
+
+    match solve_outcome(&sub, config)? {
+        SolveOutcome::Infeasible { .. } => Ok(true),
+        SolveOutcome::Optimal { .. } => Ok(false),
+        outcome => Err(SolverError::NotSolved(config.backend, Box::new(outcome))),
+    }
+}
@@ -0,0 +1,274 @@
+//! Writes problems out in the formats external solvers read, so a failing
+//! solve can be reproduced offline: CBF for any `ConicProblem`, MPS and LP
+//! for the purely linear ones, and LP for a `Milp`.
+
+use std::fs::File;
+use std::io::{BufWriter, Error, ErrorKind, Result, Write};
+
+use crate::milp::{Milp, Relation};
+use crate::solver::{csc_columns, Cone, ConicProblem, SparseRow};
+
+/// Conic Benchmark Format (version 3), as read by MOSEK, SCS and Clarabel's
+/// test tools. CBF writes `A x + b in K`, so rows go out negated, and its
+/// exponential cone is ordered (z, y, x) where ours is (x, y, z).
+pub fn write_cbf(problem: &ConicProblem, path: &str) -> Result<()> {
+    let mut w = BufWriter::new(File::create(path)?);
+    writeln!(w, "VER\n3\n")?;
+
+    let alphas: Vec<f64> = problem
+        .cones
+        .iter()
+        .filter_map(|cone| match *cone {
+            Cone::Power(alpha) => Some(alpha),
+            _ => None,
+        })
+        .collect();
+    if !alphas.is_empty() {
+        writeln!(w, "POWCONES\n{} {}", alphas.len(), 2 * alphas.len())?;
+        for alpha in &alphas {
+            writeln!(w, "2\n{}\n{}", alpha, 1.0 - alpha)?;
+        }
+        writeln!(w)?;
+    }
+
+    writeln!(w, "OBJSENSE\nMIN\n")?;
+    writeln!(w, "VAR\n{} 1\nF {}\n", problem.n_vars, problem.n_vars)?;
+
+    writeln!(w, "CON\n{} {}", problem.n_rows(), problem.cones.len())?;
+    let mut power = 0;
+    for cone in &problem.cones {
+        match *cone {
+            Cone::Zero(n) => writeln!(w, "L= {}", n)?,
+            Cone::NonNeg(n) => writeln!(w, "L+ {}", n)?,
+            Cone::SecondOrder(n) => writeln!(w, "Q {}", n)?,
+            Cone::Exp => writeln!(w, "EXP 3")?,
+            Cone::Power(_) => {
+                writeln!(w, "@{}:POW 3", power)?;
+                power += 1;
+            }
+        }
+    }
+    writeln!(w)?;
+
+    // CBF row of each of our rows
+    let mut cbf_row: Vec<usize> = (0..problem.n_rows()).collect();
+    let mut row = 0;
+    for cone in &problem.cones {
+        if let Cone::Exp = cone {
+            cbf_row.swap(row, row + 2);
+        }
+        row += cone.dim();
+    }
+
+    let objective: SparseRow = problem
+        .c
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| **c != 0.0)
+        .map(|(j, c)| (j, *c))
+        .collect();
+    if !objective.is_empty() {
+        writeln!(w, "OBJACOORD\n{}", objective.len())?;
+        for (j, c) in &objective {
+            writeln!(w, "{} {}", j, c)?;
+        }
+        writeln!(w)?;
+    }
+
+    if problem.nnz() > 0 {
+        writeln!(w, "ACOORD\n{}", problem.nnz())?;
+        for (r, coefs) in problem.a.iter().enumerate() {
+            for (j, a) in coefs {
+                writeln!(w, "{} {} {}", cbf_row[r], j, -a)?;
+            }
+        }
+        writeln!(w)?;
+    }
+
+    let rhs: Vec<(usize, f64)> = problem
+        .b
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| **b != 0.0)
+        .map(|(r, b)| (cbf_row[r], *b))
+        .collect();
+    if !rhs.is_empty() {
+        writeln!(w, "BCOORD\n{}", rhs.len())?;
+        for (r, b) in &rhs {
+            writeln!(w, "{} {}", r, b)?;
+        }
+    }
+
+    w.flush()
+}
+
+/// Free MPS. Only zero and nonnegative cones have an MPS form; anything
+/// else is refused with `InvalidInput`, write those problems as CBF.
+pub fn write_mps(problem: &ConicProblem, path: &str) -> Result<()> {
+    let relations = linear_relations(problem)?;
+    let mut w = BufWriter::new(File::create(path)?);
+
+    writeln!(w, "NAME CFMM")?;
+    writeln!(w, "ROWS")?;
+    writeln!(w, " N obj")?;
+    for (r, relation) in relations.iter().enumerate() {
+        let kind = match relation {
+            Relation::Eq => "E",
+            Relation::Leq => "L",
+            Relation::Geq => "G",
+        };
+        writeln!(w, " {} r{}", kind, r)?;
+    }
+
+    writeln!(w, "COLUMNS")?;
+    let (colptr, rowval, nzval) = csc_columns(&problem.a, problem.n_vars);
+    for j in 0..problem.n_vars {
+        // every column is listed, even one without entries, so none is lost
+        writeln!(w, "    x{} obj {}", j, problem.c[j])?;
+        for k in colptr[j]..colptr[j + 1] {
+            writeln!(w, "    x{} r{} {}", j, rowval[k], nzval[k])?;
+        }
+    }
+
+    writeln!(w, "RHS")?;
+    for (r, b) in problem.b.iter().enumerate() {
+        if *b != 0.0 {
+            writeln!(w, "    rhs r{} {}", r, b)?;
+        }
+    }
+
+    writeln!(w, "BOUNDS")?;
+    for j in 0..problem.n_vars {
+        writeln!(w, " FR bnd x{}", j)?;
+    }
+    writeln!(w, "ENDATA")?;
+    w.flush()
+}
+
+/// CPLEX LP format, linear problems only (see `write_mps`). Block labels go
+/// out as comments above their rows.
+pub fn write_lp(problem: &ConicProblem, path: &str) -> Result<()> {
+    let relations = linear_relations(problem)?;
+    let names: Vec<String> = (0..problem.n_vars).map(|j| format!("x{}", j)).collect();
+    let mut w = BufWriter::new(File::create(path)?);
+
+    let objective: SparseRow = problem.c.iter().cloned().enumerate().collect();
+    writeln!(w, "Minimize")?;
+    write!(w, " obj:")?;
+    write_terms(&mut w, &objective, &names)?;
+    writeln!(w)?;
+
+    writeln!(w, "Subject To")?;
+    let mut rows = problem.a.iter().zip(&problem.b).zip(&relations).enumerate();
+    for (cone, label) in problem.cones.iter().zip(&problem.labels) {
+        if !label.is_empty() {
+            writeln!(w, "\\ {}", label)?;
+        }
+        for (r, ((a, b), &relation)) in rows.by_ref().take(cone.dim()) {
+            write!(w, " r{}:", r)?;
+            write_terms(&mut w, a, &names)?;
+            writeln!(w, " {} {}", relation_sign(relation), b)?;
+        }
+    }
+
+    writeln!(w, "Bounds")?;
+    for name in &names {
+        writeln!(w, " {} free", name)?;
+    }
+    writeln!(w, "End")?;
+    w.flush()
+}
+
+/// CPLEX LP format of a mixed-integer program, with its own variable and
+/// row names, bounds and integrality.
+pub fn write_milp_lp(milp: &Milp, path: &str) -> Result<()> {
+    let names: Vec<String> = milp.vars.iter().map(|v| v.name.clone()).collect();
+    let mut w = BufWriter::new(File::create(path)?);
+
+    writeln!(w, "Maximize")?;
+    write!(w, " obj:")?;
+    write_terms(&mut w, &milp.objective, &names)?;
+    writeln!(w)?;
+
+    writeln!(w, "Subject To")?;
+    for row in &milp.rows {
+        write!(w, " {}:", row.name)?;
+        write_terms(&mut w, &row.terms, &names)?;
+        writeln!(w, " {} {}", relation_sign(row.relation), row.rhs)?;
+    }
+
+    writeln!(w, "Bounds")?;
+    for var in &milp.vars {
+        match (var.lower.is_finite(), var.upper.is_finite()) {
+            (false, false) => writeln!(w, " {} free", var.name)?,
+            (true, true) => writeln!(w, " {} <= {} <= {}", var.lower, var.name, var.upper)?,
+            (true, false) => writeln!(w, " {} >= {}", var.name, var.lower)?,
+            (false, true) => writeln!(w, " -inf <= {} <= {}", var.name, var.upper)?,
+        }
+    }
+
+    let integers: Vec<&str> = milp
+        .vars
+        .iter()
+        .filter(|v| v.integer)
+        .map(|v| v.name.as_str())
+        .collect();
+    if !integers.is_empty() {
+        writeln!(w, "General")?;
+        for name in integers {
+            writeln!(w, " {}", name)?;
+        }
+    }
+    writeln!(w, "End")?;
+    w.flush()
+}
+
+// `a . x (relation) b` of every row, or an error naming the first cone that
+// is not linear.
+fn linear_relations(problem: &ConicProblem) -> Result<Vec<Relation>> {
+    let mut relations = Vec::with_capacity(problem.n_rows());
+    for cone in &problem.cones {
+        match *cone {
+            Cone::Zero(n) => relations.extend(std::iter::repeat_n(Relation::Eq, n)),
+            // b - a . x >= 0
+            Cone::NonNeg(n) => relations.extend(std::iter::repeat_n(Relation::Leq, n)),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "{:?} cone has no linear form, write the problem as CBF",
+                        cone
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(relations)
+}
+
+fn relation_sign(relation: Relation) -> &'static str {
+    match relation {
+        Relation::Leq => "<=",
+        Relation::Eq => "=",
+        Relation::Geq => ">=",
+    }
+}
+
+// " + 2 x0 - 3 x1", or " 0 x0" for an empty row so it still parses.
+fn write_terms<W: Write>(w: &mut W, terms: &SparseRow, names: &[String]) -> Result<()> {
+    let mut empty = true;
+    for (j, coef) in terms {
+        if *coef == 0.0 {
+            continue;
+        }
+        let sign = if *coef < 0.0 { "-" } else { "+" };
+        write!(w, " {} {} {}", sign, coef.abs(), names[*j])?;
+        empty = false;
+    }
+    if empty {
+        if let Some(name) = names.first() {
+            write!(w, " 0 {}", name)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use ethers::types::Address;
+use std::collections::HashMap;
+
+use crate::cfmm::Cfmm;
+
+/// What one whole token is worth in the numeraire and how many decimals its
+/// base unit has, to express numeraire amounts in the token's base units.
+#[derive(Debug, Clone, Copy)]
+pub struct TokenUnits {
+    // numeraire units per whole token
+    pub price: f64,
+    pub decimals: u32,
+}
+
+impl TokenUnits {
+    pub fn new(price: f64, decimals: u32) -> Self {
+        TokenUnits { price, decimals }
+    }
+
+    /// Base units of the token worth `value` in the numeraire.
+    pub fn from_numeraire(&self, value: f64) -> f64 {
+        value / self.price * 10f64.powi(self.decimals as i32)
+    }
+}
+
+/// Execution cost of a trade: a fixed base per transaction plus a cost per
+/// hop by pool type, priced at `gas_price` and converted to the numeraire
+/// through the native token's price. Profits measured in a token rather than
+/// the numeraire (a cycle's start token) are charged through that token's
+/// entry in `tokens`.
+///
+/// The default charges nothing (zero gas price), so profits are unchanged
+/// until prices are set.
+#[derive(Debug, Clone)]
+pub struct GasModel {
+    // per transaction: intrinsic 21000 plus the router call
+    pub base: u64,
+    pub univ2_hop: u64,
+    pub constant_sum_hop: u64,
+    // fill of one signed quote
+    pub order_book_hop: u64,
+    pub balancer_hop: u64,
+    // gwei per unit of gas
+    pub gas_price: f64,
+    // numeraire units per native token (1e18 wei)
+    pub native_price: f64,
+    // trades netting less than this after gas are dropped, in the numeraire
+    pub min_net_profit: f64,
+    // price and decimals of the tokens profits are measured in
+    pub tokens: HashMap<Address, TokenUnits>,
+}
+
+impl Default for GasModel {
+    fn default() -> Self {
+        GasModel {
+            base: 40_000,
+            univ2_hop: 60_000,
+            constant_sum_hop: 90_000,
+            order_book_hop: 80_000,
+            balancer_hop: 110_000,
+            gas_price: 0.0,
+            native_price: 0.0,
+            min_net_profit: 0.0,
+            tokens: HashMap::new(),
+        }
+    }
+}
+
+impl GasModel {
+    /// Default gas amounts at `gas_price` gwei, the native token worth
+    /// `native_price` in the numeraire.
+    pub fn new(gas_price: f64, native_price: f64) -> Self {
+        GasModel {
+            gas_price,
+            native_price,
+            ..GasModel::default()
+        }
+    }
+
+    /// Prices gas and profits in base units of `token`.
+    pub fn with_token(mut self, token: Address, units: TokenUnits) -> Self {
+        self.tokens.insert(token, units);
+        self
+    }
+
+    pub fn hop_gas(&self, pool: &Cfmm) -> u64 {
+        match pool {
+            Cfmm::UniV2(_) => self.univ2_hop,
+            Cfmm::ConstantSum(_) => self.constant_sum_hop,
+            Cfmm::OrderBook(_) => self.order_book_hop,
+            Cfmm::Balancer(_) => self.balancer_hop,
+        }
+    }
+
+    /// Gas of one transaction swapping through `pools`.
+    pub fn gas(&self, pools: &[Cfmm]) -> u64 {
+        self.base + pools.iter().map(|pool| self.hop_gas(pool)).sum::<u64>()
+    }
+
+    /// Numeraire cost of one transaction swapping through `pools`.
+    pub fn cost(&self, pools: &[Cfmm]) -> f64 {
+        self.gas(pools) as f64 * self.gas_price * 1e-9 * self.native_price
+    }
+
+    /// Cost of one transaction swapping through `pools`, in base units of
+    /// `token`. A non-zero cost in a token without `TokenUnits` is infinite,
+    /// so nothing priced in it passes `accepts_in`.
+    pub fn cost_in(&self, pools: &[Cfmm], token: Address) -> f64 {
+        self.in_units(self.cost(pools), token)
+    }
+
+    /// Whether a profit after gas is worth sending.
+    pub fn accepts(&self, net_profit: f64) -> bool {
+        net_profit > 0.0 && net_profit >= self.min_net_profit
+    }
+
+    /// `accepts` for a profit in base units of `token`.
+    pub fn accepts_in(&self, net_profit: f64, token: Address) -> bool {
+        net_profit > 0.0 && net_profit >= self.in_units(self.min_net_profit, token)
+    }
+
+    // Numeraire `value` in base units of `token`.
+    fn in_units(&self, value: f64, token: Address) -> f64 {
+        if value == 0.0 {
+            return 0.0;
+        }
+        self.tokens
+            .get(&token)
+            .map_or(f64::INFINITY, |units| units.from_numeraire(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfmm::UniV2Pool;
+
+    #[test]
+    fn numeraire_values_in_base_units() {
+        // one numeraire unit of a stablecoin at par and of a token worth 2000
+        let usdc = TokenUnits::new(1.0, 6);
+        let weth = TokenUnits::new(2000.0, 18);
+        assert_eq!(usdc.from_numeraire(1.0), 1e6);
+        assert!((weth.from_numeraire(1.0) - 5e14).abs() <= 1e-12 * 5e14);
+        assert!((weth.from_numeraire(2000.0) - 1e18).abs() <= 1e-12 * 1e18);
+    }
+
+    #[test]
+    fn gas_in_a_token_without_units_is_never_worth_paying() {
+        let priced = Address::from([1; 20]);
+        let unpriced = Address::from([2; 20]);
+        let pools = [UniV2Pool::fixture(0x10, priced, unpriced, (1_000, 1_000))];
+        let gas = GasModel::new(20.0, 2000.0).with_token(priced, TokenUnits::new(1.0, 6));
+
+        // 100k gas at 20 gwei, the native token worth 2000: 4 in the numeraire
+        assert!((gas.cost(&pools) - 4.0).abs() <= 1e-12);
+        assert!((gas.cost_in(&pools, priced) - 4e6).abs() <= 1e-6);
+        assert!(gas.accepts_in(1e7 - gas.cost_in(&pools, priced), priced));
+
+        assert_eq!(gas.cost_in(&pools, unpriced), f64::INFINITY);
+        assert!(!gas.accepts_in(1e30 - gas.cost_in(&pools, unpriced), unpriced));
+    }
+}
@@ -1,19 +1,26 @@
 use std::collections::HashMap;
-use totsu::prelude::*;
-use totsu::totsu_core::ConeZero;
-use totsu::totsu_core::MatOp;
-use totsu::*;
-use totsu_core::solver::Operator;
 
-type La = FloatGeneric<f64>;
+use ethers::types::{Address, U256};
+use CFMM_covex_optimization::cfmm::{Cfmm, UniV2Pool};
+use CFMM_covex_optimization::gas::GasModel;
+use CFMM_covex_optimization::model::{Expr, Model};
+use CFMM_covex_optimization::rounding::DUST;
+use CFMM_covex_optimization::router::PoolTrade;
+use CFMM_covex_optimization::solver::{SolverConfig, SolverError};
+use CFMM_covex_optimization::verify::verify;
 
-const NUM_POOLS: usize = 8;
 const NUM_ASSETS: usize = 5;
 
 #[derive(Debug)]
 struct ArbitragePath {
     pools: Vec<(usize, usize)>,
     amounts: Vec<f64>,
+    // net amount per asset, received - tendered
+    net: Vec<f64>,
+    // numeraire value of `net`, before gas
+    gross_profit: f64,
+    // numeraire cost of swapping through every pool of `pools`
+    gas_cost: f64,
     profit: f64,
 }
 
@@ -35,6 +42,27 @@ impl Pool {
     }
 }
 
+fn asset_address(asset: usize) -> Address {
+    Address::from_low_u64_be(asset as u64)
+}
+
+fn pool_address(pool: &Pool) -> Address {
+    Address::from_low_u64_be(0x100 + pool.index as u64)
+}
+
+fn to_cfmm((from, to): (usize, usize), pool: &Pool) -> Cfmm {
+    Cfmm::UniV2(UniV2Pool {
+        address: pool_address(pool),
+        token0: asset_address(from),
+        token1: asset_address(to),
+        reserve0: U256::from(pool.reserve0 as u128),
+        reserve1: U256::from(pool.reserve1 as u128),
+        router_fee: U256::from((pool.fee * 10000.0).round() as u64),
+        fees0: U256::zero(),
+        fees1: U256::zero(),
+    })
+}
+
 fn create_sample_pools() -> HashMap<(usize, usize), Pool> {
     let mut graph = HashMap::new();
 
@@ -63,66 +91,23 @@ fn create_sample_pools() -> HashMap<(usize, usize), Pool> {
     graph
 }
 
-fn prepare_lp_data(
+fn find_arbitrage(
     graph: &HashMap<(usize, usize), Pool>,
-    start_asset: usize,
-) -> (MatBuild<La>, MatBuild<La>, MatBuild<La>) {
-    let n_vars = graph.len();
-    let n_constraints = NUM_ASSETS;
-
-    println!("Creating matrices with dimensions:");
-    println!("Variables: {}", n_vars);
-    println!("Constraints: {}", n_constraints);
-
-    let mut mat_g = MatBuild::new(MatType::General(n_constraints, n_vars));
-    let mut vec_h = MatBuild::new(MatType::General(n_constraints, 1));
-    let mut vec_c = MatBuild::new(MatType::General(n_vars, 1));
-
-    // Initialize to zero
-    for i in 0..n_constraints {
-        for j in 0..n_vars {
-            mat_g[(i, j)] = 0.0;
-        }
-        vec_h[(i, 0)] = 0.0;
-    }
-
-    for j in 0..n_vars {
-        vec_c[(j, 0)] -= 1e-2;
-    }
-
-    // Set up flow conservation constraints
-    for ((from, to), pool) in graph.iter() {
-        mat_g[(*from, pool.index)] = -1.0;
-        let effective_price = pool.get_spot_price(true) * (1.0 - pool.fee);
-        mat_g[(*to, pool.index)] = effective_price;
-        vec_c[(pool.index, 0)] = -effective_price;
-    }
-
-    // Print the entire constraint matrix G
-    println!("\nConstraint Matrix G:");
-    for i in 0..n_constraints {
-        print!("Row {}: ", i);
-        for j in 0..n_vars {
-            print!("{:8.4} ", mat_g[(i, j)]);
-        }
-        println!();
-    }
-
-    // Print vector h
-    println!("\nVector h:");
-    for i in 0..n_constraints {
-        println!("h[{}] = {:.4}", i, vec_h[(i, 0)]);
-    }
+    prices: &[f64],
+    numeraire: usize,
+    holdings: &[f64],
+    gas: &GasModel,
+) -> Result<Option<ArbitragePath>, Box<dyn std::error::Error>> {
+    // Value of one unit of each asset in the numeraire
+    let value: Vec<f64> = prices.iter().map(|p| p / prices[numeraire]).collect();
 
-    // Print cost vector c
-    println!("\nCost vector c:");
-    for i in 0..n_vars {
-        println!("c[{}] = {:.4}", i, vec_c[(i, 0)]);
-    }
+    // Pools in index order, so variables line up with pool numbers
+    let mut pools: Vec<(&(usize, usize), &Pool)> = graph.iter().collect();
+    pools.sort_by_key(|(_, pool)| pool.index);
 
     // Print pool information
     println!("\nPool Information:");
-    for ((from, to), pool) in graph.iter() {
+    for ((from, to), pool) in &pools {
         println!(
             "Pool {}: {} -> {}, Price = {:.4}, Fee-adjusted = {:.4}",
             pool.index,
@@ -133,130 +118,134 @@ fn prepare_lp_data(
         );
     }
 
-    // Verify constraint matrix properties
-    let mut row_sums = vec![0.0; n_constraints];
-    for i in 0..n_constraints {
-        for j in 0..n_vars {
-            let val: f64 = mat_g[(i, j)];
-            row_sums[i] += val.abs();
-        }
-    }
-    println!("\nRow sums (should be non-zero for each asset):");
-    for (i, sum) in row_sums.iter().enumerate() {
-        println!("Asset {}: {:.4}", i, sum);
+    // Backend from CFMM_SOLVER (totsu, clarabel or scs)
+    let mut config = SolverConfig::from_env()?;
+    config.eps = 1e-6; // Relax from 1e-9
+    config.max_iter = 500000; // Increase iterations
+    config.verbose = true;
+
+    // Each pool sells `from` for `to` on its exact constant-product curve
+    let mut model = Model::new();
+    let mut trade = Vec::with_capacity(pools.len());
+    let mut receive = Vec::with_capacity(pools.len());
+    for (_, pool) in &pools {
+        let t = model.nonneg_var(&format!("pool {} trade", pool.index));
+        let r = model.nonneg_var(&format!("pool {} receive", pool.index));
+        let k = (1.0 - config.tolerance) * pool.reserve0 * pool.reserve1;
+        model.hyperbolic(
+            (1.0 - pool.fee) * t + pool.reserve0,
+            -r + pool.reserve1,
+            k.sqrt(),
+            &format!("pool {} constant product", pool.index),
+        );
+        trade.push(t);
+        receive.push(r);
     }
 
-    let m = n_constraints;
-    let n = n_vars;
-
-    // Use a larger workspace size
-    let required_len = 2 * (m + n) +     // Variables
-                      2 * m * n +         // Matrix operations
-                      3 * m +             // Residuals
-                      3 * n +             // Search directions
-                      n * m +             // Hessian
-                      m * m +             // Additional matrix ops
-                      n * n +             // More matrix ops
-                      1000; // Extra buffer
-
-    println!("\nRequired workspace length: {}", required_len);
-    let workspace = vec![0.0; required_len];
-
-    println!("Final matrix dimensions:");
-    println!("G: {}x{}", mat_g.size().0, mat_g.size().1);
-    println!("h: {}x{}", vec_h.size().0, vec_h.size().1);
-    println!("c: {}x{}", vec_c.size().0, vec_c.size().1);
-
-    (vec_c, mat_g, vec_h)
-}
-
-fn find_arbitrage(
-    graph: &HashMap<(usize, usize), Pool>,
-    start_asset: usize,
-) -> Result<Option<ArbitragePath>, Box<dyn std::error::Error>> {
-    let (mut vec_c, mut mat_g, mut vec_h) = prepare_lp_data(graph, start_asset);
-
-    let n_vars = graph.len();
-
-    let cost_coeffs: Vec<f64> = (0..n_vars).map(|i| vec_c[(i, 0)]).collect();
-
-    // let vec_c_op: MatOp<La> = vec_c.as_op();
-    // let mat_g_op: MatOp<La> = mat_g.as_op();
-    // let vec_h_op: MatOp<La> = vec_h.as_op();
-
-    let mat_a = MatBuild::new(MatType::General(0, n_vars));
-    let vec_b = MatBuild::new(MatType::General(0, 1));
-
-    let mut prob = ProbLP::new(vec_c.clone(), mat_g.clone(), vec_h.clone(), mat_a, vec_b);
-
-    let cone = ConeZero::<La>::new();
-
-    let mut solver = Solver::<La>::new().par(|p| {
-        p.eps_acc = 1e-6; // Relax from 1e-9
-        p.eps_inf = 1e-6; // Add infeasibility tolerance
-        p.max_iter = Some(500000); // Increase iterations
-        p.log_period = 1000;
-    });
+    // Net amount of each asset over all pools
+    let net: Vec<Expr> = (0..NUM_ASSETS)
+        .map(|asset| {
+            let mut net = Expr::default();
+            for (p, ((from, to), _)) in pools.iter().enumerate() {
+                if *to == asset {
+                    net = net + receive[p];
+                }
+                if *from == asset {
+                    net = net - trade[p];
+                }
+            }
+            net
+        })
+        .collect();
+
+    // Trades may start from any asset we hold, spending at most what we hold;
+    // every other asset has to come back at least even
+    for asset in 0..NUM_ASSETS {
+        model.geq(
+            net[asset].clone(),
+            -holdings[asset],
+            &format!("holdings of asset {}", asset),
+        );
+    }
 
-    let (op_c, op_a, op_b, cone, mut work) = prob.problem();
+    // Profit is the numeraire value of the net trade across all assets
+    model.maximise(Expr::sum(
+        net.iter()
+            .zip(&value)
+            .map(|(net, value)| *value * net.clone()),
+    ));
 
     println!("\nStarting solver...");
-    match solver.solve((op_c, op_a, op_b, cone, &mut work)) {
-        Ok(result) => {
-            let (x, y) = result;
-
-            if x.len() != n_vars {
-                println!(
-                    "Solution vector length mismatch. Expected: {}, Got: {}",
-                    n_vars,
-                    x.len()
-                );
-                return Ok(None);
-            }
+    let solution = model.solve(&config)?;
+    let profit = solution.objective;
+
+    // Replay the trades on the pools
+    let cfmms: Vec<Cfmm> = pools
+        .iter()
+        .map(|(pair, pool)| to_cfmm(**pair, pool))
+        .collect();
+    let trades: Vec<PoolTrade> = pools
+        .iter()
+        .enumerate()
+        .map(|(p, ((from, to), pool))| PoolTrade {
+            pool: pool_address(pool),
+            tokens: vec![asset_address(*from), asset_address(*to)],
+            received: vec![0.0, solution.value(receive[p]).max(0.0)],
+            tendered: vec![solution.value(trade[p]).max(0.0), 0.0],
+        })
+        .collect();
+    let asset_values: HashMap<Address, f64> = value
+        .iter()
+        .enumerate()
+        .map(|(asset, value)| (asset_address(asset), *value))
+        .collect();
+    let verification = verify(&cfmms, &trades, &asset_values, profit);
+    if !verification.accepts(config.verify_slack()) {
+        println!("{}", verification);
+        return Err(Box::new(SolverError::Unverified(verification)));
+    }
 
-            let profit: f64 = -x
-                .iter()
-                .zip(cost_coeffs.iter())
-                .map(|(&xi, &ci)| xi * ci)
-                .sum::<f64>();
-
-            if profit > 1e-6 {
-                let mut active_pools = Vec::new();
-                let mut active_amounts = Vec::new();
-
-                for (i, &amount) in x.iter().enumerate() {
-                    if amount > 1e-6 {
-                        if let Some(((from, to), _)) = graph.iter().find(|(_, p)| p.index == i) {
-                            active_pools.push((*from, *to));
-                            active_amounts.push(amount);
-                        }
-                    }
-                }
+    // within the solver's own slack of zero, relative to the capital at
+    // stake, is no arbitrage at all
+    let capital: f64 = holdings.iter().zip(&value).map(|(h, v)| h * v).sum();
+    if profit <= config.verify_slack() * capital.max(1.0) {
+        return Ok(None);
+    }
 
-                if !active_pools.is_empty() {
-                    Ok(Some(ArbitragePath {
-                        pools: active_pools,
-                        amounts: active_amounts,
-                        profit,
-                    }))
-                } else {
-                    Ok(None)
-                }
-            } else {
-                Ok(None)
-            }
-            // ... (rest of the function remains the same)
-        }
-        Err(e) => {
-            println!("Solver error: {:?}", e);
-            println!("This might indicate:");
-            println!("1. Infeasible problem");
-            println!("2. Numerical issues");
-            println!("3. Insufficient workspace");
-            println!("4. Incorrect problem formulation");
-            Err(Box::new(e))
+    let mut active_pools = Vec::new();
+    let mut active_amounts = Vec::new();
+    let mut active_cfmms = Vec::new();
+    for (p, (pair, _)) in pools.iter().enumerate() {
+        let amount = solution.value(trade[p]);
+        // less than a unit cannot be sent, so the pool stays untouched
+        if amount > DUST {
+            active_pools.push(**pair);
+            active_amounts.push(amount);
+            active_cfmms.push(cfmms[p].clone());
         }
     }
+
+    // Gas is paid per pool touched, which the convex model cannot see, so
+    // it is charged on the solution found
+    let gas_cost = gas.cost(&active_cfmms);
+    println!(
+        "Gross profit {:.6}, gas {:.6} over {} pools",
+        profit,
+        gas_cost,
+        active_cfmms.len()
+    );
+    if !gas.accepts(profit - gas_cost) {
+        return Ok(None);
+    }
+
+    Ok(Some(ArbitragePath {
+        pools: active_pools,
+        amounts: active_amounts,
+        net: net.iter().map(|net| solution.eval(net)).collect(),
+        gross_profit: profit,
+        gas_cost,
+        profit: profit - gas_cost,
+    }))
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -264,25 +253,108 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     println!("Number of pools: {}", graph.len());
 
-    for start_asset in 0..NUM_ASSETS {
-        println!(
-            "\nSearching for arbitrage starting from asset {}",
-            start_asset
-        );
-
-        match find_arbitrage(&graph, start_asset)? {
-            Some(arb) => {
-                println!("Found profitable arbitrage:");
-                println!("Path: {:?}", arb.pools);
-                println!("Amounts: {:?}", arb.amounts);
-                println!("Expected profit: {:.6}", arb.profit);
-            }
-            None => println!(
-                "No profitable arbitrage found starting from asset {}",
-                start_asset
-            ),
+    // External prices (any common unit), profit is counted in asset 0
+    let prices = [1.0, 0.49, 0.95, 0.96, 0.97];
+    let numeraire = 0;
+    // What the wallet holds; trades may start from assets 0 and 2
+    let holdings = [1000.0, 0.0, 500.0, 0.0, 0.0];
+    // 20 gwei, one native token worth 2000 of asset 0
+    let gas = GasModel {
+        min_net_profit: 0.5,
+        ..GasModel::new(20.0, 2000.0)
+    };
+
+    println!(
+        "\nSearching for arbitrage in asset {} from holdings {:?}",
+        numeraire, holdings
+    );
+
+    match find_arbitrage(&graph, &prices, numeraire, &holdings, &gas) {
+        Ok(Some(arb)) => {
+            println!("Found profitable arbitrage:");
+            println!("Path: {:?}", arb.pools);
+            println!("Amounts: {:?}", arb.amounts);
+            println!("Net per asset: {:?}", arb.net);
+            println!("Gross profit: {:.6}", arb.gross_profit);
+            println!("Gas cost: {:.6}", arb.gas_cost);
+            println!("Expected net profit: {:.6}", arb.profit);
         }
+        Ok(None) => println!("No profitable arbitrage found"),
+        Err(e) => println!("Arbitrage search failed: {}", e),
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fair_pools_have_no_arbitrage() {
+        // a cycle at par loses the fee on every hop
+        let graph: HashMap<(usize, usize), Pool> = [(0, 1), (1, 2), (2, 0)]
+            .into_iter()
+            .enumerate()
+            .map(|(index, pair)| {
+                let pool = Pool {
+                    index,
+                    reserve0: 1000.0,
+                    reserve1: 1000.0,
+                    fee: 0.003,
+                };
+                (pair, pool)
+            })
+            .collect();
+        let holdings = [1000.0, 0.0, 500.0, 0.0, 0.0];
+        let arb = find_arbitrage(
+            &graph,
+            &[1.0; NUM_ASSETS],
+            0,
+            &holdings,
+            &GasModel::default(),
+        )
+        .unwrap();
+        assert!(arb.is_none());
+    }
+
+    #[test]
+    fn reported_pools_trade_at_least_a_unit() {
+        let holdings = [1000.0, 0.0, 500.0, 0.0, 0.0];
+        let prices = [1.0, 0.49, 0.95, 0.96, 0.97];
+        let arb = find_arbitrage(
+            &create_sample_pools(),
+            &prices,
+            0,
+            &holdings,
+            &GasModel::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert!(!arb.pools.is_empty());
+        assert!(arb.amounts.iter().all(|amount| *amount > DUST));
+        assert!(arb.profit > 0.0);
+    }
+
+    #[test]
+    fn gas_is_charged_once_per_active_pool() {
+        let holdings = [1000.0, 0.0, 500.0, 0.0, 0.0];
+        let prices = [1.0, 0.49, 0.95, 0.96, 0.97];
+        // 1 gwei, the native token worth 2000 of asset 0
+        let gas = GasModel::new(1.0, 2000.0);
+        let arb = find_arbitrage(&create_sample_pools(), &prices, 0, &holdings, &gas)
+            .unwrap()
+            .unwrap();
+
+        let mut pools = arb.pools.clone();
+        pools.sort();
+        pools.dedup();
+        assert_eq!(pools.len(), arb.pools.len());
+
+        // one transaction, one hop per pool
+        let units = gas.base + gas.univ2_hop * arb.pools.len() as u64;
+        let cost = units as f64 * 1e-9 * 2000.0;
+        assert!((arb.gas_cost - cost).abs() <= 1e-12);
+        assert!((arb.profit - (arb.gross_profit - cost)).abs() <= 1e-12);
+    }
+}
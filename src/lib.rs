@@ -1,6 +1,24 @@
+// Lets modules that double as binaries (node_edges) name the library the same
+// way from either side.
+extern crate self as CFMM_covex_optimization;
+
 //pub mod Linear_optimization;
 //pub mod SOCP;
+pub mod cfmm;
+pub mod diagnose;
+pub mod export;
+pub mod gas;
 //pub mod graphical;
 pub mod main;
+pub mod milp;
+pub mod model;
 //pub mod multi;
 pub mod node_edges;
+pub mod order_book;
+pub mod robust;
+pub mod rounding;
+pub mod router;
+#[cfg(feature = "scs")]
+mod scs;
+pub mod solver;
+pub mod verify;
@@ -0,0 +1,125 @@
+use good_lp::{
+    default_solver, variable, Expression, ProblemVariables, ResolutionError, Solution, SolverModel,
+    Variable,
+};
+
+use crate::solver::SparseRow;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Relation {
+    Leq,
+    Eq,
+    Geq,
+}
+
+#[derive(Debug, Clone)]
+pub struct MilpVar {
+    pub name: String,
+    // infinite when unbounded
+    pub lower: f64,
+    pub upper: f64,
+    pub integer: bool,
+}
+
+/// `terms . x (relation) rhs`
+#[derive(Debug, Clone)]
+pub struct MilpRow {
+    pub name: String,
+    pub terms: SparseRow,
+    pub relation: Relation,
+    pub rhs: f64,
+}
+
+/// Mixed-integer linear program, maximising `objective . x`. Kept as plain
+/// data so the same model can be handed to CBC through good_lp or written
+/// out (see `export::write_milp_lp`).
+#[derive(Debug, Clone, Default)]
+pub struct Milp {
+    pub vars: Vec<MilpVar>,
+    pub objective: SparseRow,
+    pub rows: Vec<MilpRow>,
+}
+
+impl Milp {
+    pub fn new() -> Self {
+        Milp::default()
+    }
+
+    /// Continuous variable in `[lower, upper]`; returns its column.
+    pub fn add_var(&mut self, name: &str, lower: f64, upper: f64) -> usize {
+        self.vars.push(MilpVar {
+            name: name.to_string(),
+            lower,
+            upper,
+            integer: false,
+        });
+        self.vars.len() - 1
+    }
+
+    /// 0/1 variable; returns its column.
+    pub fn add_binary(&mut self, name: &str) -> usize {
+        self.vars.push(MilpVar {
+            name: name.to_string(),
+            lower: 0.0,
+            upper: 1.0,
+            integer: true,
+        });
+        self.vars.len() - 1
+    }
+
+    pub fn add_row(&mut self, name: &str, terms: SparseRow, relation: Relation, rhs: f64) {
+        self.rows.push(MilpRow {
+            name: name.to_string(),
+            terms,
+            relation,
+            rhs,
+        });
+    }
+
+    pub fn objective_value(&self, values: &[f64]) -> f64 {
+        self.objective.iter().map(|(j, c)| c * values[*j]).sum()
+    }
+
+    /// Solves with good_lp's default solver (CBC); one value per variable.
+    pub fn solve(&self) -> Result<Vec<f64>, ResolutionError> {
+        let mut problem = ProblemVariables::new();
+        let vars: Vec<Variable> = self
+            .vars
+            .iter()
+            .map(|v| {
+                let mut def = variable().name(v.name.clone());
+                if v.lower.is_finite() {
+                    def = def.min(v.lower);
+                }
+                if v.upper.is_finite() {
+                    def = def.max(v.upper);
+                }
+                if v.integer {
+                    def = def.integer();
+                }
+                problem.add(def)
+            })
+            .collect();
+
+        let expression = |terms: &SparseRow| {
+            terms
+                .iter()
+                .fold(Expression::default(), |acc, (j, c)| acc + *c * vars[*j])
+        };
+
+        let mut model = problem
+            .maximise(expression(&self.objective))
+            .using(default_solver);
+        for row in &self.rows {
+            let lhs = expression(&row.terms);
+            model = match row.relation {
+                Relation::Leq => model.with(lhs.leq(row.rhs)),
+                Relation::Eq => model.with(lhs.eq(row.rhs)),
+                Relation::Geq => model.with(lhs.geq(row.rhs)),
+            };
+        }
+
+        let solution = model.solve()?;
+        Ok(vars.iter().map(|v| solution.value(*v)).collect())
+    }
+}
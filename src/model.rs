@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::ops::{Add, Mul, Neg, Sub};
+
+use crate::solver::{solve, Cone, ConicProblem, Solution, SolverConfig, SolverError};
+
+/// Handle to a model variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Var(usize);
+
+/// Affine expression `sum coef * var + constant`.
+#[derive(Debug, Clone, Default)]
+pub struct Expr {
+    terms: Vec<(Var, f64)>,
+    constant: f64,
+}
+
+impl Expr {
+    pub fn constant(value: f64) -> Self {
+        Expr {
+            terms: Vec::new(),
+            constant: value,
+        }
+    }
+
+    pub fn sum<I: IntoIterator<Item = Expr>>(exprs: I) -> Self {
+        exprs.into_iter().fold(Expr::default(), |acc, e| acc + e)
+    }
+
+    pub fn eval(&self, values: &[f64]) -> f64 {
+        self.constant
+            + self
+                .terms
+                .iter()
+                .map(|(var, coef)| coef * values[var.0])
+                .sum::<f64>()
+    }
+}
+
+impl From<Var> for Expr {
+    fn from(var: Var) -> Self {
+        Expr {
+            terms: vec![(var, 1.0)],
+            constant: 0.0,
+        }
+    }
+}
+
+impl From<f64> for Expr {
+    fn from(value: f64) -> Self {
+        Expr::constant(value)
+    }
+}
+
+impl<T: Into<Expr>> Add<T> for Expr {
+    type Output = Expr;
+
+    fn add(mut self, other: T) -> Expr {
+        let other = other.into();
+        self.terms.extend(other.terms);
+        self.constant += other.constant;
+        self
+    }
+}
+
+impl<T: Into<Expr>> Sub<T> for Expr {
+    type Output = Expr;
+
+    fn sub(self, other: T) -> Expr {
+        self + (-other.into())
+    }
+}
+
+impl Neg for Expr {
+    type Output = Expr;
+
+    fn neg(self) -> Expr {
+        self * -1.0
+    }
+}
+
+impl Mul<f64> for Expr {
+    type Output = Expr;
+
+    fn mul(mut self, factor: f64) -> Expr {
+        for (_, coef) in &mut self.terms {
+            *coef *= factor;
+        }
+        self.constant *= factor;
+        self
+    }
+}
+
+impl<T: Into<Expr>> Add<T> for Var {
+    type Output = Expr;
+
+    fn add(self, other: T) -> Expr {
+        Expr::from(self) + other
+    }
+}
+
+impl<T: Into<Expr>> Sub<T> for Var {
+    type Output = Expr;
+
+    fn sub(self, other: T) -> Expr {
+        Expr::from(self) - other
+    }
+}
+
+impl Neg for Var {
+    type Output = Expr;
+
+    fn neg(self) -> Expr {
+        -Expr::from(self)
+    }
+}
+
+impl Mul<f64> for Var {
+    type Output = Expr;
+
+    fn mul(self, factor: f64) -> Expr {
+        Expr::from(self) * factor
+    }
+}
+
+impl Mul<Var> for f64 {
+    type Output = Expr;
+
+    fn mul(self, var: Var) -> Expr {
+        Expr::from(var) * self
+    }
+}
+
+impl Mul<Expr> for f64 {
+    type Output = Expr;
+
+    fn mul(self, expr: Expr) -> Expr {
+        expr * self
+    }
+}
+
+// Expressions that must lie in `cone`, in cone order.
+#[derive(Debug, Clone)]
+struct Constraint {
+    cone: Cone,
+    exprs: Vec<Expr>,
+    label: String,
+}
+
+/// Conic model over named variables. Constraints are written as expressions
+/// and compiled to a `ConicProblem`, so no builder has to track row or column
+/// offsets by hand.
+#[derive(Debug, Clone, Default)]
+pub struct Model {
+    names: Vec<String>,
+    index: HashMap<String, Var>,
+    constraints: Vec<Constraint>,
+    // maximised
+    objective: Expr,
+}
+
+/// Optimal values of a model's variables.
+#[derive(Debug, Clone)]
+pub struct ModelSolution {
+    pub values: Vec<f64>,
+    // objective as maximised (negated for `minimise`)
+    pub objective: f64,
+    // backend solution, rows in constraint order
+    pub raw: Solution,
+}
+
+impl ModelSolution {
+    pub fn value(&self, var: Var) -> f64 {
+        self.values[var.0]
+    }
+
+    pub fn eval(&self, expr: &Expr) -> f64 {
+        expr.eval(&self.values)
+    }
+}
+
+impl Model {
+    pub fn new() -> Self {
+        Model::default()
+    }
+
+    /// New free variable; names must be unique.
+    pub fn var(&mut self, name: &str) -> Var {
+        assert!(
+            !self.index.contains_key(name),
+            "duplicate variable {}",
+            name
+        );
+        let var = Var(self.names.len());
+        self.names.push(name.to_string());
+        self.index.insert(name.to_string(), var);
+        var
+    }
+
+    /// New variable with `var >= 0`.
+    pub fn nonneg_var(&mut self, name: &str) -> Var {
+        let var = self.var(name);
+        self.geq(var, 0.0, &format!("{} >= 0", name));
+        var
+    }
+
+    pub fn get(&self, name: &str) -> Option<Var> {
+        self.index.get(name).copied()
+    }
+
+    pub fn name(&self, var: Var) -> &str {
+        &self.names[var.0]
+    }
+
+    pub fn n_vars(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn maximise<E: Into<Expr>>(&mut self, objective: E) {
+        self.objective = objective.into();
+    }
+
+    pub fn minimise<E: Into<Expr>>(&mut self, objective: E) {
+        self.objective = -objective.into();
+    }
+
+    pub fn leq<L: Into<Expr>, R: Into<Expr>>(&mut self, lhs: L, rhs: R, label: &str) {
+        self.add(Cone::NonNeg(1), vec![rhs.into() - lhs.into()], label);
+    }
+
+    pub fn geq<L: Into<Expr>, R: Into<Expr>>(&mut self, lhs: L, rhs: R, label: &str) {
+        self.add(Cone::NonNeg(1), vec![lhs.into() - rhs.into()], label);
+    }
+
+    pub fn eq<L: Into<Expr>, R: Into<Expr>>(&mut self, lhs: L, rhs: R, label: &str) {
+        self.add(Cone::Zero(1), vec![lhs.into() - rhs.into()], label);
+    }
+
+    /// ||xs|| <= t
+    pub fn soc<T: Into<Expr>>(&mut self, t: T, xs: Vec<Expr>, label: &str) {
+        let mut exprs = vec![t.into()];
+        exprs.extend(xs);
+        self.add(Cone::SecondOrder(exprs.len()), exprs, label);
+    }
+
+    /// u * v >= root^2 with u, v >= 0, as ||(2 root, u - v)|| <= u + v.
+    pub fn hyperbolic<U: Into<Expr>, V: Into<Expr>>(&mut self, u: U, v: V, root: f64, label: &str) {
+        let (u, v) = (u.into(), v.into());
+        self.soc(
+            u.clone() + v.clone(),
+            vec![Expr::constant(2.0 * root), u - v],
+            label,
+        );
+    }
+
+    /// y * exp(x / y) <= z, y > 0
+    pub fn exp_cone<X, Y, Z>(&mut self, x: X, y: Y, z: Z, label: &str)
+    where
+        X: Into<Expr>,
+        Y: Into<Expr>,
+        Z: Into<Expr>,
+    {
+        self.add(Cone::Exp, vec![x.into(), y.into(), z.into()], label);
+    }
+
+    fn add(&mut self, cone: Cone, exprs: Vec<Expr>, label: &str) {
+        self.constraints.push(Constraint {
+            cone,
+            exprs,
+            label: label.to_string(),
+        });
+    }
+
+    /// Standard-form problem; each constraint becomes one labelled block.
+    pub fn compile(&self) -> ConicProblem {
+        let n_vars = self.n_vars();
+        let mut prob = ConicProblem::new(n_vars);
+
+        // minimise -objective
+        for (var, coef) in &self.objective.terms {
+            prob.c[var.0] -= coef;
+        }
+
+        // expression e in K  <=>  b - A x in K with b = constant, A = -coefs
+        for constraint in &self.constraints {
+            let mut rows = Vec::with_capacity(constraint.exprs.len());
+            let mut rhs = Vec::with_capacity(constraint.exprs.len());
+            for expr in &constraint.exprs {
+                rows.push(
+                    expr.terms
+                        .iter()
+                        .map(|(var, coef)| (var.0, -coef))
+                        .collect(),
+                );
+                rhs.push(expr.constant);
+            }
+            let first = prob.cones.len();
+            prob.add_block(constraint.cone, rows, rhs);
+            prob.label_from(first, &constraint.label);
+        }
+
+        prob
+    }
+
+    pub fn solve(&self, config: &SolverConfig) -> Result<ModelSolution, SolverError> {
+        let raw = solve(&self.compile(), config)?;
+        let values = raw.x.clone();
+        let objective = self.objective.eval(&values);
+        Ok(ModelSolution {
+            values,
+            objective,
+            raw,
+        })
+    }
+}
@@ -1,124 +1,148 @@
-use totsu::prelude::*;
-use totsu::{MatBuild, ProbLP, ProbSOCP};
-type La = FloatGeneric<f64>;
+use ethers::types::{Address, U256};
+use std::collections::HashMap;
+use CFMM_covex_optimization::cfmm::{Cfmm, UniV2Pool};
+use CFMM_covex_optimization::diagnose::explain_infeasibility;
+use CFMM_covex_optimization::model::{Expr, Model};
+use CFMM_covex_optimization::router::PoolTrade;
+use CFMM_covex_optimization::solver::{SolveOutcome, SolverConfig, SolverError};
+use CFMM_covex_optimization::verify::verify;
 
 fn main() {
     let num_assets = 5; // Five assets
-    let num_pools = 8; // Eight pools
-    let reserves: Vec<Vec<f64>> = vec![
-        vec![100.0, 10.0],
-        vec![90.0, 15.0],
-        vec![80.0, 8.0],
-        vec![70.0, 12.0],
-        vec![60.0, 14.0],
-        vec![110.0, 20.0],
-        vec![130.0, 25.0],
-        vec![120.0, 18.0],
+                        // Eight pools: (Asset-A, Asset-B) and their reserves
+    let pools: Vec<((usize, usize), [f64; 2])> = vec![
+        ((0, 1), [100.0, 10.0]),
+        ((1, 2), [90.0, 15.0]),
+        ((2, 3), [80.0, 8.0]),
+        ((3, 4), [70.0, 12.0]),
+        ((4, 0), [60.0, 14.0]),
+        ((0, 2), [110.0, 20.0]),
+        ((1, 3), [130.0, 25.0]),
+        ((2, 4), [120.0, 18.0]),
     ];
     let fee: f64 = 0.997;
-    let n_vars = num_pools * 2; // Two variables per pool (buy/sell)
 
-    // Objective vector `c` for profit maximization
-    let mut vec_c = MatBuild::<FloatGeneric<f64>>::new(MatType::General(n_vars, 1));
-    for i in 0..n_vars {
-        vec_c[(i, 0)] = if i % 2 == 0 { -1.0 } else { 1.0 };
-    }
+    // Backend from CFMM_SOLVER (totsu, clarabel or scs)
+    let mut config = SolverConfig::from_env().expect("Unknown solver backend");
+    config.eps = 1e-12;
+    config.max_iter = 1000000;
+    config.verbose = true;
+
+    let mut model = Model::new();
 
-    // Calculate total constraints count
-    let num_constraints = 2 * num_pools + num_assets + n_vars; // Separate out each constraint type
-    let mut mat_g = MatBuild::<FloatGeneric<f64>>::new(MatType::General(num_constraints, n_vars));
-    let mut vec_h = MatBuild::<FloatGeneric<f64>>::new(MatType::General(num_constraints, 1));
+    // Each pool takes Asset-A in and pays Asset-B out
+    let mut trade = Vec::new();
+    let mut receive = Vec::new();
+    for (i, ((_, _), reserve)) in pools.iter().enumerate() {
+        let t = model.nonneg_var(&format!("pool {} trade", i + 1));
+        let r = model.nonneg_var(&format!("pool {} receive", i + 1));
 
-    // Constant product constraints for each pool
-    for (i, reserve) in reserves.iter().enumerate() {
-        mat_g[(i * 2, i * 2)] = reserve[1];
-        mat_g[(i * 2, i * 2 + 1)] = -reserve[0] / fee;
-        vec_h[(i * 2, 0)] = reserve[0] * reserve[1] * 0.01;
+        // (R_A + fee * trade)(R_B - receive) >= (1 - tolerance) R_A R_B
+        let k = (1.0 - config.tolerance) * reserve[0] * reserve[1];
+        model.hyperbolic(
+            fee * t + reserve[0],
+            -r + reserve[1],
+            k.sqrt(),
+            &format!("pool {} constant product", i + 1),
+        );
 
-        mat_g[(i * 2 + 1, i * 2)] = -1.0;
-        mat_g[(i * 2 + 1, i * 2 + 1)] = 1.0;
-        vec_h[(i * 2 + 1, 0)] = 0.0;
+        trade.push(t);
+        receive.push(r);
     }
 
-    // Balance constraints across assets
-    for asset_idx in 0..num_assets {
-        for pool_idx in 0..num_pools {
-            let asset_in_pool = (asset_idx + pool_idx) % num_assets;
-            let row = 2 * num_pools + asset_idx;
-            if pool_idx * 2 < n_vars {
-                mat_g[(row, pool_idx * 2)] = if asset_in_pool == asset_idx {
-                    1.0
-                } else {
-                    -1.0
-                };
-                mat_g[(row, pool_idx * 2 + 1)] = if asset_in_pool == asset_idx {
-                    -1.0
-                } else {
-                    1.0
-                };
+    // Net amount of each asset: what the pools pay out of it minus what goes in
+    let net: Vec<Expr> = (0..num_assets)
+        .map(|asset| {
+            let mut net = Expr::default();
+            for (i, ((asset_a, asset_b), _)) in pools.iter().enumerate() {
+                if *asset_b == asset {
+                    net = net + receive[i];
+                }
+                if *asset_a == asset {
+                    net = net - trade[i];
+                }
             }
-            vec_h[(row, 0)] = 0.0;
-        }
-    }
+            net
+        })
+        .collect();
 
-    // Non-negativity constraints
-    for i in 0..n_vars {
-        let row = 2 * num_pools + num_assets + i;
-        mat_g[(row, i)] = -1.0;
-        vec_h[(row, 0)] = 0.0;
+    // Never end short of any asset, and collect the profit in asset 0
+    for (asset, balance) in net.iter().enumerate().skip(1) {
+        model.geq(balance.clone(), 0.0, &format!("balance of asset {}", asset));
     }
+    model.maximise(net[0].clone());
 
-    let mat_a = MatBuild::<FloatGeneric<f64>>::new(MatType::General(0, n_vars));
-    let vec_b = MatBuild::<FloatGeneric<f64>>::new(MatType::General(0, 1));
+    match model.solve(&config) {
+        Ok(solution) => {
+            // Replay the trades on the pools
+            let cfmms: Vec<Cfmm> = pools
+                .iter()
+                .enumerate()
+                .map(|(i, ((asset_a, asset_b), reserve))| {
+                    Cfmm::UniV2(UniV2Pool {
+                        address: Address::from_low_u64_be(0x100 + i as u64),
+                        token0: Address::from_low_u64_be(*asset_a as u64),
+                        token1: Address::from_low_u64_be(*asset_b as u64),
+                        reserve0: U256::from(reserve[0] as u128),
+                        reserve1: U256::from(reserve[1] as u128),
+                        router_fee: U256::from(((1.0 - fee) * 10000.0).round() as u64),
+                        fees0: U256::zero(),
+                        fees1: U256::zero(),
+                    })
+                })
+                .collect();
+            let trades: Vec<PoolTrade> = cfmms
+                .iter()
+                .enumerate()
+                .map(|(i, pool)| PoolTrade {
+                    pool: pool.address(),
+                    tokens: pool.tokens(),
+                    received: vec![0.0, solution.value(receive[i]).max(0.0)],
+                    tendered: vec![solution.value(trade[i]).max(0.0), 0.0],
+                })
+                .collect();
+            // Profit is counted in asset 0
+            let prices: HashMap<Address, f64> = HashMap::from([(Address::from_low_u64_be(0), 1.0)]);
 
-    let mut prob = ProbLP::new(vec_c.clone(), mat_g.clone(), vec_h.clone(), mat_a, vec_b);
-    let mut solver = Solver::new();
-
-    solver = solver.par(|p| {
-        p.eps_acc = 1e-12;
-        p.eps_inf = 1e-12;
-        p.eps_zero = 1e-14;
-        p.max_iter = Some(1000000);
-        p.log_period = 10000;
-    });
+            let profit = solution.objective;
+            let verification = verify(&cfmms, &trades, &prices, profit);
+            if !verification.accepts(config.verify_slack()) {
+                println!("Solution rejected, trades break the pool invariants:");
+                println!("{}", verification);
+                return;
+            }
 
-    let (op_c, op_a, op_b, cone, mut work) = prob.problem();
-    match solver.solve((op_c, op_a, op_b, cone, &mut work)) {
-        Ok((x, _y)) => {
             println!("Optimal solution found:");
-            for i in 0..num_pools {
+            for i in 0..pools.len() {
                 println!(
                     "Pool {} - Trade Asset-A: {:.12}, Receive Asset-B: {:.12}",
                     i + 1,
-                    x[i * 2],
-                    x[i * 2 + 1]
+                    solution.value(trade[i]),
+                    solution.value(receive[i])
                 );
             }
-            let profit: f64 =
-                x.iter().step_by(2).sum::<f64>() - x.iter().skip(1).step_by(2).sum::<f64>();
-            println!("Solution vector: {:?}", x);
-            println!("Profit: {:.16}", profit);
-
-            for (i, reserve) in reserves.iter().enumerate() {
-                let new_reserve_a = reserve[0] + x[i * 2];
-                let new_reserve_b = reserve[1] - x[i * 2 + 1] / fee;
-                let cp_satisfied =
-                    (new_reserve_a * new_reserve_b >= reserve[0] * reserve[1] * 0.9999);
-                println!(
-                    "Pool {} constant product formula satisfied: {}",
-                    i + 1,
-                    cp_satisfied
-                );
+            for (asset, net) in net.iter().enumerate() {
+                println!("Asset {} net: {:.12}", asset, solution.eval(net));
             }
+            println!("Profit: {:.16}", profit);
+            println!("{}", verification);
         }
         Err(e) => {
-            println!("Error: {:?}", e);
-            println!("Objective vector c:");
-            println!("{:?}", vec_c);
-            println!("Constraint matrix G:");
-            println!("{:?}", mat_g);
-            println!("Constraint vector h:");
-            println!("{:?}", vec_h);
+            println!("Error: {}", e);
+            if let SolverError::NotSolved(_, outcome) = &e {
+                if let SolveOutcome::Infeasible { .. } = outcome.as_ref() {
+                    match explain_infeasibility(&model.compile(), &config) {
+                        Ok(Some(conflict)) => {
+                            println!("These constraints cannot hold together:");
+                            for name in conflict {
+                                println!("  {}", name);
+                            }
+                        }
+                        Ok(None) => println!("Feasible once the objective is dropped"),
+                        Err(e) => println!("Could not explain infeasibility: {}", e),
+                    }
+                }
+            }
         }
     }
 }
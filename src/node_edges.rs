@@ -1,12 +1,16 @@
 use ethers::types::Address;
 use ethers::types::U256;
-use good_lp::{
-    default_solver,
-    solvers::{Solution, SolverModel},
-    variables, Expression, ModelWithSOS1, ProblemVariables, Solution as LpSolution, Variable,
+pub use CFMM_covex_optimization::cfmm::{
+    best_input, cycle_start_token, execute_path, floor_units, golden_section_max, path_amount_in,
+    path_amounts_in, path_curve, path_tokens, simulate_path, Cfmm, ConstantSumPool, UniV2Pool,
 };
-use petgraph::algo::dijkstra;
-use petgraph::dot::{Config, Dot};
+use CFMM_covex_optimization::export::write_milp_lp;
+use CFMM_covex_optimization::gas::{GasModel, TokenUnits};
+use CFMM_covex_optimization::milp::{Milp, Relation};
+use CFMM_covex_optimization::rounding::{round_trades, trade_legs, ExecutionPlan, Slippage, DUST};
+use CFMM_covex_optimization::router::{PoolTrade, Router};
+use CFMM_covex_optimization::solver::SolverConfig;
+use CFMM_covex_optimization::verify::{path_trades, verify};
 use petgraph::graph::{Graph, NodeIndex};
 use petgraph::visit::EdgeRef;
 use serde::{Deserialize, Serialize};
@@ -15,7 +19,6 @@ use std::collections::HashSet;
 use std::fs::File;
 use std::io::Result;
 use std::io::Write;
-use std::str::FromStr;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Pools {
@@ -30,6 +33,11 @@ impl Pools {
         Ok(storage.pools)
     }
 
+    /// Path of pools, each traded from token0 to token1.
+    pub fn new(pools: Vec<UniV2Pool>) -> Self {
+        Pools { pools }
+    }
+
     pub fn calculate_profit(&self, input_amount: f64) -> f64 {
         let mut amount = input_amount;
 
@@ -42,68 +50,252 @@ impl Pools {
 
         amount
     }
+
+    /// Input that makes the path pay `amount_out`: `calculate_profit` run
+    /// backwards from the last pool. Infinite when a pool cannot pay what
+    /// the next one needs.
+    pub fn calculate_input(&self, amount_out: f64) -> f64 {
+        let mut amount = amount_out;
+
+        for pool in self.pools.iter().rev() {
+            let reserve0 = pool.reserve0.as_u128() as f64;
+            let reserve1 = pool.reserve1.as_u128() as f64;
+            let fee = 1.0 - (pool.router_fee.as_u64() as f64 / 10000.0);
+            if amount >= reserve1 {
+                return f64::INFINITY;
+            }
+            amount = (reserve0 * amount) / ((reserve1 - amount) * fee);
+        }
+
+        amount
+    }
+
+    /// What `input_amount` makes over the path after paying for its gas,
+    /// in start-token units.
+    pub fn net_profit(&self, input_amount: f64, gas: &GasModel) -> f64 {
+        let path: Vec<Cfmm> = self.pools.iter().cloned().map(Cfmm::from).collect();
+        let gas_cost = self
+            .pools
+            .first()
+            .map_or(0.0, |pool| gas.cost_in(&path, pool.token0));
+        self.calculate_profit(input_amount) - input_amount - gas_cost
+    }
+
+    /// Profit of borrowing `borrowed` of the start token, running the path
+    /// and paying the loan back with its fee.
+    pub fn flash_loan_profit(&self, borrowed: f64, loan: &FlashLoan) -> f64 {
+        self.calculate_profit(borrowed) - loan.repayment(borrowed)
+    }
+
+    /// Best amount to borrow for the path and what it makes after repayment.
+    /// Hops chain as `out = a x / (b + c x)`, the same form as one pool, so
+    /// the optimum is where the path's marginal rate meets `1 + fee`.
+    pub fn flash_loan(&self, loan: &FlashLoan) -> FlashArb {
+        // out = a x / (b + c x), starting from out = x
+        let (mut a, mut b, mut c) = (1.0, 1.0, 0.0);
+        for pool in &self.pools {
+            let reserve0 = pool.reserve0.as_u128() as f64;
+            let reserve1 = pool.reserve1.as_u128() as f64;
+            let fee = 1.0 - (pool.router_fee.as_u64() as f64 / 10000.0);
+            (a, b, c) = (reserve1 * fee * a, reserve0 * b, reserve0 * c + fee * a);
+        }
+
+        // d/dx (a x / (b + c x)) = a b / (b + c x)^2 = 1 + fee
+        let borrowed = if c > 0.0 {
+            ((a * b / (1.0 + loan.fee)).sqrt() - b).max(0.0) / c
+        } else {
+            0.0
+        };
+        let output = self.calculate_profit(borrowed);
+        let repaid = loan.repayment(borrowed);
+
+        FlashArb {
+            pools: self.pools.clone(),
+            borrowed,
+            output,
+            repaid,
+            profit: output - repaid,
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct UniV2Pool {
-    pub address: Address,
-    pub token0: Address,
-    pub token1: Address,
-    pub reserve0: U256,
-    pub reserve1: U256,
-    pub router_fee: U256,
-    pub fees0: U256,
-    pub fees1: U256,
+/// Where the start token of a flash-loan arbitrage is borrowed from.
+#[derive(Debug, Clone, Copy)]
+pub struct FlashLoan {
+    // fraction of the borrowed amount owed on top of it
+    pub fee: f64,
+}
+
+impl FlashLoan {
+    /// Aave V3, 0.05%.
+    pub fn aave() -> Self {
+        FlashLoan { fee: 0.0005 }
+    }
+
+    /// Balancer vault, no fee.
+    pub fn balancer() -> Self {
+        FlashLoan { fee: 0.0 }
+    }
+
+    /// UniV2 flash swap: the pool wants `borrowed / 0.997` back, its 0.3%
+    /// taken from the repayment rather than the loan.
+    pub fn uniswap_v2() -> Self {
+        FlashLoan { fee: 0.003 / 0.997 }
+    }
+
+    pub fn repayment(&self, borrowed: f64) -> f64 {
+        borrowed * (1.0 + self.fee)
+    }
+}
+
+/// A path run on borrowed capital, amounts in the start token.
+#[derive(Debug, Clone)]
+pub struct FlashArb {
+    pub pools: Vec<UniV2Pool>,
+    pub borrowed: f64,
+    // what the path pays out for `borrowed`
+    pub output: f64,
+    // borrowed amount plus the loan fee
+    pub repaid: f64,
+    pub profit: f64,
+}
+
+/// Limits of the cycle-selection MILP in `PoolGraph::find_arb`; amounts
+/// are in units of the cycles' start token.
+#[derive(Debug, Clone)]
+pub struct CycleSelection {
+    // capital shared by all selected cycles
+    pub budget: f64,
+    // largest input into a single cycle, also the big-M of its selection
+    pub max_input: f64,
+    pub max_cycles: usize,
+    // cycles `optimize_joint` searches over, the most profitable on their own
+    pub max_candidates: usize,
+    // simulated points on each cycle's output curve, past the origin
+    pub breakpoints: usize,
+    // charged once per selected cycle, in units of the cycle's start token
+    pub gas: GasModel,
+}
+
+impl Default for CycleSelection {
+    fn default() -> Self {
+        CycleSelection {
+            budget: 1156.0,
+            max_input: 1156.0,
+            max_cycles: 3,
+            max_candidates: 8,
+            breakpoints: 16,
+            gas: GasModel::default(),
+        }
+    }
+}
+
+/// One cycle of a joint plan, amounts in token units.
+#[derive(Debug, Clone)]
+pub struct JointStep {
+    // index into the cycles passed to `optimize_joint`
+    pub cycle: usize,
+    pub input: f64,
+    pub output: f64,
+}
+
+/// Cycles in execution order and their summed profit after gas.
+#[derive(Debug, Clone)]
+pub struct JointPlan {
+    pub steps: Vec<JointStep>,
+    pub profit: f64,
+}
+
+/// One route of a split swap: the pools in order, the tokens they pass
+/// through (input first, output last) and the share of the swap on it.
+#[derive(Debug, Clone)]
+pub struct SwapPath {
+    pub pools: Vec<Address>,
+    pub tokens: Vec<Address>,
+    pub amount_in: f64,
+    pub amount_out: f64,
+}
+
+/// Quote for selling `amount_in` of `token_in` for `token_out` across every
+/// pool of a graph.
+#[derive(Debug, Clone)]
+pub struct SwapQuote {
+    pub token_in: Address,
+    pub token_out: Address,
+    pub amount_in: f64,
+    // output of the convex split, before rounding
+    pub amount_out: f64,
+    pub paths: Vec<SwapPath>,
+    // the split in whole units, replayed on the pools, with amountOutMin per
+    // hop; its `end_min` is the minimum output of the swap
+    pub plan: ExecutionPlan,
 }
 
 pub struct PoolGraph {
     graph: Graph<Address, u64>,
     token_map: HashMap<Address, NodeIndex>,
-    pool_map: HashMap<(Address, Address), UniV2Pool>,
+    // one pool per token pair, for walking cycles
+    pool_map: HashMap<(Address, Address), Cfmm>,
+    // every pool, parallel ones included, for routing
+    pools: Vec<Cfmm>,
 }
 
 impl PoolGraph {
-    pub fn new(pools: &Vec<UniV2Pool>) -> Self {
+    pub fn new(pools: &[UniV2Pool]) -> Self {
+        let cfmms: Vec<Cfmm> = pools.iter().cloned().map(Cfmm::from).collect();
+        Self::from_cfmms(&cfmms)
+    }
+
+    pub fn from_cfmms(pools: &[Cfmm]) -> Self {
         let mut graph: Graph<Address, u64> = Graph::new();
         let mut token_map = HashMap::new();
         let mut pool_map = HashMap::new();
 
         // Create nodes
         for pool in pools {
-            if !token_map.contains_key(&pool.token0) {
-                let idx = graph.add_node(pool.token0);
-                token_map.insert(pool.token0, idx);
-            }
-            if !token_map.contains_key(&pool.token1) {
-                let idx = graph.add_node(pool.token1);
-                token_map.insert(pool.token1, idx);
+            for token in pool.tokens() {
+                token_map
+                    .entry(token)
+                    .or_insert_with(|| graph.add_node(token));
             }
 
-            // Add to pool map - ensure tokens are in consistent order
-            let key = if pool.token0 < pool.token1 {
-                (pool.token0, pool.token1)
-            } else {
-                (pool.token1, pool.token0)
-            };
-            pool_map.insert(key, pool.clone());
+            // Add to pool map - ensure tokens are in consistent order;
+            // multi-token pools serve every pair they hold
+            let tokens = pool.tokens();
+            for (a, &token_a) in tokens.iter().enumerate() {
+                for &token_b in &tokens[a + 1..] {
+                    let key = if token_a < token_b {
+                        (token_a, token_b)
+                    } else {
+                        (token_b, token_a)
+                    };
+                    pool_map.insert(key, pool.clone());
+                }
+            }
         }
 
         // Add edges
         for pool in pools {
-            let n1 = token_map[&pool.token0];
-            let n2 = token_map[&pool.token1];
-            graph.add_edge(n1, n2, 0);
-            graph.add_edge(n2, n1, 0);
+            let tokens = pool.tokens();
+            for (a, &token_a) in tokens.iter().enumerate() {
+                for &token_b in &tokens[a + 1..] {
+                    let n1 = token_map[&token_a];
+                    let n2 = token_map[&token_b];
+                    graph.add_edge(n1, n2, 0);
+                    graph.add_edge(n2, n1, 0);
+                }
+            }
         }
 
         PoolGraph {
             graph,
             token_map,
             pool_map,
+            pools: pools.to_vec(),
         }
     }
 
-    pub fn get_pool(&self, token0: Address, token1: Address) -> Option<&UniV2Pool> {
+    pub fn get_pool(&self, token0: Address, token1: Address) -> Option<&Cfmm> {
         let key = if token0 < token1 {
             (token0, token1)
         } else {
@@ -125,7 +317,7 @@ impl PoolGraph {
 
         cycles
             .iter()
-            .map(|cycle| cycle.iter().map(|&idx| self.graph[idx].clone()).collect())
+            .map(|cycle| cycle.iter().map(|&idx| self.graph[idx]).collect())
             .filter(|cycle: &Vec<Address>| {
                 !cycle.is_empty() && cycle[0] == start && self.verify_path_exists(cycle)
             })
@@ -168,9 +360,7 @@ impl PoolGraph {
         // Check consecutive pairs
         for window in path.windows(2) {
             //  println!("Checking pool between {} and {}", window[0], window[1]);
-            if let Some(pool) = self.get_pool(window[0], window[1]) {
-                // println!("  Found pool: {}", pool.address);
-            } else {
+            if self.get_pool(window[0], window[1]).is_none() {
                 // println!("  No pool found between these tokens!");
                 return false;
             }
@@ -178,7 +368,7 @@ impl PoolGraph {
 
         // Check final connection
         if let (Some(last), Some(first)) = (path.last(), path.first()) {
-            if (last == first) {
+            if last == first {
                 return true;
             }
             //println!("Checking final connection: {} -> {}", last, first);
@@ -191,7 +381,7 @@ impl PoolGraph {
         }
     }
 
-    pub fn convert_cycle_to_pools(&self, cycle: &[Address]) -> Option<Vec<UniV2Pool>> {
+    pub fn convert_cycle_to_pools(&self, cycle: &[Address]) -> Option<Vec<Cfmm>> {
         if !self.verify_path_exists(cycle) {
             return None;
         }
@@ -222,18 +412,25 @@ impl PoolGraph {
         for window in circular_path.windows(2) {
             if let Some(pool) = self.get_pool(window[0], window[1]) {
                 println!("\nStep: {} -> {}", window[0], window[1]);
-                println!("  Pool Address: {}", pool.address);
-                println!("  Reserves: {} / {}", pool.reserve0, pool.reserve1);
-                println!("  Router Fee: {} bps", pool.router_fee);
-                println!("  Token Taxes: {} / {} bps", pool.fees0, pool.fees1);
+                let (reserve0, reserve1) = pool.reserves();
+                println!("  Pool Address: {}", pool.address());
+                println!("  Reserves: {} / {}", reserve0, reserve1);
+                println!("  Router Fee: {} bps", pool.router_fee());
+                match pool {
+                    Cfmm::UniV2(pool) => {
+                        println!("  Token Taxes: {} / {} bps", pool.fees0, pool.fees1)
+                    }
+                    Cfmm::ConstantSum(pool) => {
+                        println!("  Constant Sum: price {}, cap {:?}", pool.price, pool.max_out)
+                    }
+                    Cfmm::OrderBook(book) => {
+                        println!("  Order Book: {} bids / {} asks", book.bids.len(), book.asks.len())
+                    }
+                    Cfmm::Balancer(pool) => println!("  Weights: {:?}", pool.weights),
+                }
 
                 // Calculate price
-                let (reserve_from, reserve_to) = if pool.token0 == window[0] {
-                    (pool.reserve0, pool.reserve1)
-                } else {
-                    (pool.reserve1, pool.reserve0)
-                };
-                let price = reserve_to.as_u128() as f64 / reserve_from.as_u128() as f64;
+                let price = pool.spot_price_to(window[0], window[1]);
                 println!("  Price: {}", price);
             }
         }
@@ -263,128 +460,204 @@ impl PoolGraph {
                     "    {:?} -> {:?} [label=\"{}\"];",
                     edge.source().index(),
                     edge.target().index(),
-                    pool.address
+                    pool.address()
                 )?;
             }
         }
 
         writeln!(file, "}}")
     }
-    pub fn find_arb(&self, cycle_pools: Vec<Vec<UniV2Pool>>) -> Vec<(Vec<UniV2Pool>, f64, f64)> {
-        println!("Analyzing {} potential cycles", cycle_pools.len());
-        let mut profitable_paths = Vec::new();
-
-        let mut problem: ProblemVariables = ProblemVariables::new();
-
-        // Step 1: Define input variables for each path (0.0 to 1000.0)
-        let x: Vec<Variable> = (0..cycle_pools.len())
-            .map(|i| {
-                problem.add(
-                    good_lp::variable::variable()
-                        .min(0.0)
-                        .max(1156.0)
-                        .name(format!("input_{}", i)),
-                )
-            })
+    /// The cycle-selection MILP `find_arb` solves. For `n` cycles,
+    /// `input_i` is column `i`, `select_i` column `n + i` and `output_i`
+    /// column `2n + i`.
+    ///
+    /// A cycle only gets input when it is selected (`input_i <= max_input *
+    /// select_i`), no pool serves two selected cycles, at most `max_cycles`
+    /// are selected and all inputs share one `budget`. `output_i` is held
+    /// under the chords between exactly simulated points of the cycle's
+    /// output curve; the curve is concave, so the chords never promise more
+    /// than the pools pay and price impact stops the input growing forever.
+    /// A selected cycle pays its gas (`selection.gas`) as a fixed charge.
+    pub fn cycle_milp(&self, cycle_pools: &[Vec<Cfmm>], selection: &CycleSelection) -> Milp {
+        let n = cycle_pools.len();
+        let mut milp = Milp::new();
+
+        // Step 1: Define input variables for each path (0.0 to max_input)
+        let x: Vec<usize> = (0..n)
+            .map(|i| milp.add_var(&format!("input_{}", i), 0.0, selection.max_input))
             .collect();
 
         // Step 2: Define binary selection variables for each path (0 to 1)
-        let z: Vec<Variable> = (0..cycle_pools.len())
-            .map(|i| {
-                problem.add(
-                    good_lp::variable::variable()
-                        .binary()
-                        .name(format!("select_{}", i)),
-                )
-            })
+        let z: Vec<usize> = (0..n)
+            .map(|i| milp.add_binary(&format!("select_{}", i)))
             .collect();
 
         // Step 3: Define profit expressions for each path
-        let profits: Vec<Expression> = cycle_pools
-            .iter()
-            .enumerate()
-            .map(|(i, cycle)| {
-                // Calculate the coefficient for this path
-                let mut coefficient = 1.0;
-                for pool in cycle {
-                    let reserve0 = pool.reserve0.as_u128() as f64;
-                    let reserve1 = pool.reserve1.as_u128() as f64;
-                    let fee = 1.0 - (pool.router_fee.as_u64() as f64 / 10000.0);
-                    coefficient *= (reserve1 / reserve0) * fee;
+        for (i, cycle) in cycle_pools.iter().enumerate() {
+            // Calculate the coefficient for this path
+            let mut coefficient = 1.0;
+            let mut curve = vec![(0.0, 0.0)];
+            if let Some(start) = cycle_start_token(cycle) {
+                let tokens = path_tokens(cycle, start);
+                for (k, pool) in cycle.iter().enumerate() {
+                    coefficient *= pool.spot_price_to(tokens[k], tokens[k + 1]);
                 }
+                curve = path_curve(cycle, start, selection.max_input, selection.breakpoints);
+                // no input past the furthest point the path can fill
+                let limit = curve.last().map_or(0.0, |(input, _)| *input);
+                milp.vars[x[i]].upper = limit;
+            }
+            println!("\nAnalyzing cycle {}", i);
+            println!("Path coefficient: {}", coefficient);
+
+            let max_output = curve.iter().fold(0.0, |m: f64, (_, out)| m.max(*out));
+            let output = milp.add_var(&format!("output_{}", i), 0.0, max_output);
+            debug_assert_eq!(output, 2 * n + i);
+
+            // output <= slope * input + intercept * selection along each chord
+            for (k, pair) in curve.windows(2).enumerate() {
+                let ((x0, y0), (x1, y1)) = (pair[0], pair[1]);
+                if x1 <= x0 {
+                    continue;
+                }
+                let slope = (y1 - y0) / (x1 - x0);
+                milp.add_row(
+                    &format!("curve_{}_{}", i, k),
+                    vec![(output, 1.0), (x[i], -slope), (z[i], -(y0 - slope * x0))],
+                    Relation::Leq,
+                    0.0,
+                );
+            }
 
-                println!("\nAnalyzing cycle {}", i);
-                println!("Path coefficient: {}", coefficient);
-
-                let input_amount = Expression::from(x[i].clone());
-                let selection = Expression::from(z[i].clone());
-
-                // Calculate estimated output using the path coefficient
-                let estimated_output = coefficient * input_amount.clone();
-
-                // Profit is (output - input) * selection
-                let mut profit = estimated_output - input_amount;
+            // Profit is output - input, less the gas of sending the cycle
+            milp.objective.push((output, 1.0));
+            milp.objective.push((x[i], -1.0));
+            let gas_cost =
+                cycle_start_token(cycle).map_or(0.0, |start| selection.gas.cost_in(cycle, start));
+            if gas_cost > 0.0 {
+                milp.objective.push((z[i], -gas_cost));
+            }
 
-                profit.add_mul(1, selection);
-                profit
-            })
-            .collect();
+            // big-M link: no input unless selected
+            milp.add_row(
+                &format!("link_{}", i),
+                vec![(x[i], 1.0), (z[i], -selection.max_input)],
+                Relation::Leq,
+                0.0,
+            );
+        }
 
-        // Create optimization objective
-        let objective = profits
-            .iter()
-            .fold(Expression::default(), |acc, expr| acc + expr.clone());
+        // Pool-disjointness: each pool's liquidity goes to one cycle at most
+        let mut users: Vec<(Address, Vec<usize>)> = Vec::new();
+        for (i, cycle) in cycle_pools.iter().enumerate() {
+            for pool in cycle {
+                match users
+                    .iter_mut()
+                    .find(|(address, _)| *address == pool.address())
+                {
+                    Some((_, cycles)) => cycles.push(i),
+                    None => users.push((pool.address(), vec![i])),
+                }
+            }
+        }
+        for (address, cycles) in users {
+            if cycles.len() > 1 {
+                milp.add_row(
+                    &format!("pool_{:?}", address),
+                    cycles.iter().map(|&i| (z[i], 1.0)).collect(),
+                    Relation::Leq,
+                    1.0,
+                );
+            }
+        }
 
-        let sum_z: Expression = z.iter().sum();
+        milp.add_row(
+            "max_cycles",
+            z.iter().map(|&j| (j, 1.0)).collect(),
+            Relation::Leq,
+            selection.max_cycles as f64,
+        );
 
         // Constraint: limit total input amount
-        let sum_x: Expression = x.iter().sum();
+        milp.add_row(
+            "budget",
+            x.iter().map(|&j| (j, 1.0)).collect(),
+            Relation::Leq,
+            selection.budget,
+        );
+
+        milp
+    }
 
-        let solution = problem
-            .maximise(objective)
-            .using(default_solver)
-            .with(sum_x.leq(1156.0))
-            .with(sum_z.eq(52.0))
-            .solve();
+    pub fn find_arb(
+        &self,
+        cycle_pools: Vec<Vec<Cfmm>>,
+        selection: &CycleSelection,
+    ) -> Vec<(Vec<Cfmm>, f64, f64)> {
+        println!("Analyzing {} potential cycles", cycle_pools.len());
+        let mut profitable_paths = Vec::new();
+
+        let n = cycle_pools.len();
+        let milp = self.cycle_milp(&cycle_pools, selection);
+        let solution = milp.solve();
 
-        print!("Raj theckrey");
         match solution {
-            Ok(solution) => {
-                for (i, &zi) in z.iter().enumerate() {
-                    println!("value of z {:?} for cycle {:?}", solution.value(zi), i);
-                    if solution.value(zi) > 0.5 {
-                        let input_amount = solution.value(x[i]);
-                        let mut current_amount = input_amount;
+            Ok(values) => {
+                for i in 0..n {
+                    println!("value of z {:?} for cycle {:?}", values[n + i], i);
+                    // selected but left without input: nothing to trade
+                    if values[n + i] > 0.5 && values[i] > 0.0 {
+                        let input_amount = values[i];
+                        let start = match cycle_start_token(&cycle_pools[i]) {
+                            Some(start) => start,
+                            None => continue,
+                        };
 
                         println!("\nAnalyzing path {}:", i);
                         println!("Input amount: {}", input_amount);
 
-                        println!("x vaue for {:?} is {:?}", i, current_amount);
-
-                        // Calculate actual output through the path
-                        for pool in &cycle_pools[i] {
-                            let reserve0 = pool.reserve0.as_u128() as f64;
-                            let reserve1 = pool.reserve1.as_u128() as f64;
-                            let fee = 1.0 - (pool.router_fee.as_u64() as f64 / 10000.0);
-
-                            // Apply constant product formula
-                            current_amount = (reserve1 * current_amount * fee)
-                                / (reserve0 + current_amount * fee);
+                        // Replay the path on the pools' own swap formulas
+                        let trades = match path_trades(&cycle_pools[i], start, input_amount) {
+                            Some(trades) => trades,
+                            None => {
+                                println!("Path {} rejected, a pool cannot fill it", i);
+                                continue;
+                            }
+                        };
+                        for trade in &trades {
+                            println!(
+                                "After pool {}: {}",
+                                trade.pool,
+                                trade.received.iter().sum::<f64>()
+                            );
+                        }
 
-                            println!("After pool {}: {}", pool.address, current_amount);
+                        let predicted = values[2 * n + i] - input_amount;
+                        let prices: HashMap<Address, f64> = HashMap::from([(start, 1.0)]);
+                        let verification = verify(&cycle_pools[i], &trades, &prices, predicted);
+                        println!("{}", verification);
+                        if !verification.accepts(SolverConfig::default().verify_slack()) {
+                            println!("Path {} rejected, it breaks a pool invariant", i);
+                            continue;
                         }
 
-                        let profit = current_amount - input_amount;
-                        if profit > 0.0 {
-                            println!("jnbchcsbhb");
+                        let gross = verification.realized;
+                        let gas_cost = selection.gas.cost_in(&cycle_pools[i], start);
+                        let profit = gross - gas_cost;
+                        if selection.gas.accepts_in(profit, start) {
                             println!("\nFound profitable path!");
                             println!("Path length: {}", cycle_pools[i].len());
                             println!("Input: {}", input_amount);
-                            println!("Output: {}", current_amount);
-                            println!("Profit: {}", profit);
+                            println!("Output: {}", input_amount + gross);
+                            println!("Gross profit: {}", gross);
+                            println!("Gas cost: {}", gas_cost);
+                            println!("Net profit: {}", profit);
+                            println!("Predicted profit: {}", verification.predicted);
                             println!("Return: {:.2}%", (profit / input_amount) * 100.0);
 
                             profitable_paths.push((cycle_pools[i].clone(), input_amount, profit));
+                        } else {
+                            println!("Path {} nets {} after gas, below the threshold", i, profit);
                         }
                     }
                 }
@@ -392,14 +665,278 @@ impl PoolGraph {
             Err(e) => println!("Failed to solve optimization problem: {:?}", e),
         }
 
-        // Sort profitable paths by profit
+        // Sort profitable paths by net profit
         profitable_paths.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
-        println!("nnnnnnnnnn {:?}", profitable_paths);
         profitable_paths
     }
 
+    /// Cycles run one after another on shared pools, each executed (in token
+    /// units) on the reserves the cycles before it left behind, so a pool's
+    /// liquidity is never counted twice. A heuristic in two passes: a
+    /// depth-first search over orderings of up to `max_cycles` cycles picks
+    /// the sequence, sizing each cycle greedily for its own profit; then
+    /// coordinate ascent re-sizes the chosen sequence jointly, one input at a
+    /// time with the others held, until no input raises the total. Only the
+    /// `max_candidates` cycles most profitable on their own are searched.
+    ///
+    /// Each input is capped by `max_input` and `budget`, capital coming back
+    /// at the end of every cycle. Profits are net of each cycle's gas and
+    /// summed in start-token units, as for cycles from one `detect_cycles`
+    /// call.
+    pub fn optimize_joint(
+        &self,
+        cycle_pools: &[Vec<Cfmm>],
+        selection: &CycleSelection,
+    ) -> JointPlan {
+        let mut state: HashMap<Address, Cfmm> = HashMap::new();
+        for cycle in cycle_pools {
+            for pool in cycle {
+                state.insert(pool.address(), pool.clone());
+            }
+        }
+
+        // the search is exponential in the cycles, so keep the best alone
+        let max_input = selection.max_input.min(selection.budget);
+        let mut standalone: Vec<(usize, f64)> = cycle_pools
+            .iter()
+            .enumerate()
+            .filter_map(|(i, cycle)| {
+                let start = cycle_start_token(cycle)?;
+                let input = best_input(cycle, start, max_input);
+                let output = simulate_path(cycle, start, input)?;
+                Some((i, output - input - selection.gas.cost_in(cycle, start)))
+            })
+            .filter(|(_, profit)| *profit > 0.0)
+            .collect();
+        standalone.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
+        let candidates: Vec<usize> = standalone
+            .iter()
+            .take(selection.max_candidates)
+            .map(|(i, _)| *i)
+            .collect();
+
+        let mut best = JointPlan {
+            steps: Vec::new(),
+            profit: 0.0,
+        };
+        let mut steps = Vec::new();
+        let mut used = vec![false; cycle_pools.len()];
+        Self::extend_joint(
+            cycle_pools,
+            selection,
+            &candidates,
+            &state,
+            &mut used,
+            &mut steps,
+            0.0,
+            &mut best,
+        );
+        Self::resize_joint(cycle_pools, selection, &state, best)
+    }
+
+    // Depth-first over orderings of `candidates`: every cycle still
+    // profitable on `state` is tried next.
+    #[allow(clippy::too_many_arguments)]
+    fn extend_joint(
+        cycle_pools: &[Vec<Cfmm>],
+        selection: &CycleSelection,
+        candidates: &[usize],
+        state: &HashMap<Address, Cfmm>,
+        used: &mut [bool],
+        steps: &mut Vec<JointStep>,
+        profit: f64,
+        best: &mut JointPlan,
+    ) {
+        if profit > best.profit {
+            best.steps = steps.clone();
+            best.profit = profit;
+        }
+        if steps.len() >= selection.max_cycles {
+            return;
+        }
+
+        for &i in candidates {
+            if used[i] {
+                continue;
+            }
+            let start = match cycle_start_token(&cycle_pools[i]) {
+                Some(start) => start,
+                None => continue,
+            };
+
+            // the cycle's pools as the steps so far left them
+            let mut pools: Vec<Cfmm> = cycle_pools[i]
+                .iter()
+                .map(|pool| state[&pool.address()].clone())
+                .collect();
+            let input = best_input(&pools, start, selection.max_input.min(selection.budget));
+            let amount_in = floor_units(input);
+            if amount_in.is_zero() {
+                continue;
+            }
+            let amount_out = match execute_path(&mut pools, start, amount_in) {
+                Some(amount_out) => amount_out,
+                None => continue,
+            };
+            let input = amount_in.as_u128() as f64;
+            let output = amount_out.as_u128() as f64;
+            let gas_cost = selection.gas.cost_in(&cycle_pools[i], start);
+            if !selection.gas.accepts_in(output - input - gas_cost, start) {
+                continue;
+            }
+
+            let mut next = state.clone();
+            for pool in pools {
+                next.insert(pool.address(), pool);
+            }
+            used[i] = true;
+            steps.push(JointStep {
+                cycle: i,
+                input,
+                output,
+            });
+            Self::extend_joint(
+                cycle_pools,
+                selection,
+                candidates,
+                &next,
+                used,
+                steps,
+                profit + output - input - gas_cost,
+                best,
+            );
+            steps.pop();
+            used[i] = false;
+        }
+    }
+
+    // Coordinate ascent on the inputs of `plan`'s cycles, order kept: each
+    // input in turn is set to what maximises the total with the others
+    // held, for as long as a round still raises the total.
+    fn resize_joint(
+        cycle_pools: &[Vec<Cfmm>],
+        selection: &CycleSelection,
+        state: &HashMap<Address, Cfmm>,
+        plan: JointPlan,
+    ) -> JointPlan {
+        let cycles: Vec<usize> = plan.steps.iter().map(|step| step.cycle).collect();
+        let mut inputs: Vec<f64> = plan.steps.iter().map(|step| step.input).collect();
+        let max_input = selection.max_input.min(selection.budget);
+        let mut best = plan;
+
+        for _ in 0..20 {
+            let before = best.profit;
+            for k in 0..cycles.len() {
+                let with_input = |x: f64| {
+                    let mut trial = inputs.clone();
+                    trial[k] = x;
+                    trial
+                };
+                let x = golden_section_max(
+                    |x| {
+                        Self::run_joint(cycle_pools, selection, state, &cycles, &with_input(x))
+                            .map_or(f64::NEG_INFINITY, |plan| plan.profit)
+                    },
+                    max_input,
+                );
+                let trial = with_input(x);
+                let plan = Self::run_joint(cycle_pools, selection, state, &cycles, &trial);
+                if let Some(plan) = plan.filter(|plan| plan.profit > best.profit) {
+                    inputs = trial;
+                    best = plan;
+                }
+            }
+            if best.profit <= before + 1e-9 * before.abs().max(1.0) {
+                break;
+            }
+        }
+        best
+    }
+
+    // Runs `cycles` in order with `inputs`, in token units, on the pools as
+    // `state` holds them; `None` when a pool cannot fill its part.
+    fn run_joint(
+        cycle_pools: &[Vec<Cfmm>],
+        selection: &CycleSelection,
+        state: &HashMap<Address, Cfmm>,
+        cycles: &[usize],
+        inputs: &[f64],
+    ) -> Option<JointPlan> {
+        let mut state = state.clone();
+        let mut steps = Vec::with_capacity(cycles.len());
+        let mut profit = 0.0;
+
+        for (&i, &input) in cycles.iter().zip(inputs) {
+            let start = cycle_start_token(&cycle_pools[i])?;
+            let mut pools: Vec<Cfmm> = cycle_pools[i]
+                .iter()
+                .map(|pool| state[&pool.address()].clone())
+                .collect();
+            let amount_in = floor_units(input);
+            let amount_out = execute_path(&mut pools, start, amount_in)?;
+            let input = amount_in.as_u128() as f64;
+            let output = amount_out.as_u128() as f64;
+            profit += output - input - selection.gas.cost_in(&cycle_pools[i], start);
+            for pool in pools {
+                state.insert(pool.address(), pool);
+            }
+            steps.push(JointStep {
+                cycle: i,
+                input,
+                output,
+            });
+        }
+
+        Some(JointPlan { steps, profit })
+    }
+
+    /// Every pool of the graph once, parallel pools on the same pair
+    /// included, in address order.
+    pub fn pools(&self) -> Vec<Cfmm> {
+        let mut pools: Vec<Cfmm> = Vec::new();
+        for pool in &self.pools {
+            if !pools.iter().any(|p| p.address() == pool.address()) {
+                pools.push(pool.clone());
+            }
+        }
+        pools.sort_by_key(|pool| pool.address());
+        pools
+    }
+
+    /// Best split of selling `amount` of `token_in` for `token_out` over all
+    /// pools of the graph. It is the router's problem with only `token_out`
+    /// valued and a wallet holding just `amount` of `token_in`, so every
+    /// other token has to come back even; the pool trades are then split
+    /// into paths and rounded to whole-unit swaps with `slippage`.
+    pub fn quote_swap(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        amount: f64,
+        config: &SolverConfig,
+        slippage: &Slippage,
+    ) -> std::result::Result<SwapQuote, Box<dyn std::error::Error>> {
+        let prices = HashMap::from([(token_out, 1.0)]);
+        let holdings = HashMap::from([(token_in, amount)]);
+        let router = Router::new(self.pools(), &prices).with_inventory(&holdings);
+        let solution = router.solve(config)?;
+
+        let paths = split_paths(&solution.trades, token_in, token_out);
+        let units = HashMap::from([(token_in, floor_units(amount))]);
+        let plan = round_trades(&router.pools, &solution.trades, &units, slippage)?;
+
+        Ok(SwapQuote {
+            token_in,
+            token_out,
+            amount_in: amount,
+            amount_out: solution.net[router.token_index(token_out)],
+            paths,
+            plan,
+        })
+    }
+
     pub fn print_profitable_paths(
-        profitable_paths: &[(Vec<UniV2Pool>, f64, f64)],
+        profitable_paths: &[(Vec<Cfmm>, f64, f64)],
         mut starting_token: Address,
     ) {
         for (i, (path, input, profit)) in profitable_paths.iter().enumerate() {
@@ -413,24 +950,120 @@ impl PoolGraph {
 
             for (j, pool) in path.iter().enumerate() {
                 // Ensure the path follows the token flow
-                if pool.token0 == starting_token {
-                    current_token = pool.token1;
-                } else if pool.token1 == current_token {
-                    current_token = pool.token0;
+                if pool.token0() == starting_token {
+                    current_token = pool.token1();
+                } else if pool.token1() == current_token {
+                    current_token = pool.token0();
                 } else {
                     println!("  Error: Invalid token flow in path.");
                     break;
                 }
 
                 println!("  Step {}: {} -> {}", j + 1, starting_token, current_token);
-                println!("    Pool: {}", pool.address);
-                println!("    Reserves: {} / {}", pool.reserve0, pool.reserve1);
-                println!("    Fee: {} bps", pool.router_fee);
+                let (reserve0, reserve1) = pool.reserves();
+                println!("    Pool: {}", pool.address());
+                println!("    Reserves: {} / {}", reserve0, reserve1);
+                println!("    Fee: {} bps", pool.router_fee());
                 starting_token = current_token;
             }
         }
     }
 }
+// A pool trade as an edge of the flow from the input to the output token.
+struct Flow {
+    pool: Address,
+    from: Address,
+    to: Address,
+    // input not yet assigned to a path
+    remaining: f64,
+    // output per unit of input, averaged over the trade
+    rate: f64,
+}
+
+/// Splits the pool trades of a swap into paths from `token_in` to
+/// `token_out`: each round follows the widest remaining edges to the output
+/// and takes as much as the narrowest of them, measured in `token_in`, can
+/// still carry, emptying it. Flow that never reaches `token_out` (a loop
+/// inside the route) is left out.
+fn split_paths(trades: &[PoolTrade], token_in: Address, token_out: Address) -> Vec<SwapPath> {
+    let mut flows = Vec::new();
+    for trade in trades {
+        let (ins, outs) = trade_legs(trade);
+        if ins.len() == 1 && outs.len() == 1 {
+            flows.push(Flow {
+                pool: trade.pool,
+                from: trade.tokens[ins[0]],
+                to: trade.tokens[outs[0]],
+                remaining: trade.tendered[ins[0]],
+                rate: trade.received[outs[0]] / trade.tendered[ins[0]],
+            });
+        }
+    }
+
+    let mut paths = Vec::new();
+    let mut seen = vec![token_in];
+    // every round empties one edge
+    while let Some(route) = route_to(&flows, token_in, token_out, &mut seen) {
+        // largest input, in token_in, each edge can still take
+        let mut scale = 1.0;
+        let mut narrowest = route[0];
+        let mut amount_in = f64::INFINITY;
+        for &e in &route {
+            if flows[e].remaining / scale < amount_in {
+                amount_in = flows[e].remaining / scale;
+                narrowest = e;
+            }
+            scale *= flows[e].rate;
+        }
+
+        let mut amount = amount_in;
+        let mut tokens = vec![token_in];
+        for &e in &route {
+            flows[e].remaining -= amount;
+            amount *= flows[e].rate;
+            tokens.push(flows[e].to);
+        }
+        flows[narrowest].remaining = 0.0;
+
+        paths.push(SwapPath {
+            pools: route.iter().map(|&e| flows[e].pool).collect(),
+            tokens,
+            amount_in,
+            amount_out: amount,
+        });
+        seen.truncate(1);
+    }
+    paths
+}
+
+// Depth-first path of edges with more than dust left from `token` to `token_out` through
+// tokens not in `seen`, widest edge first.
+fn route_to(
+    flows: &[Flow],
+    token: Address,
+    token_out: Address,
+    seen: &mut Vec<Address>,
+) -> Option<Vec<usize>> {
+    if token == token_out {
+        return Some(Vec::new());
+    }
+    let mut next: Vec<usize> = (0..flows.len())
+        .filter(|&e| flows[e].from == token && flows[e].remaining >= DUST)
+        .filter(|&e| !seen.contains(&flows[e].to))
+        .collect();
+    next.sort_by(|&a, &b| flows[b].remaining.partial_cmp(&flows[a].remaining).unwrap());
+
+    for e in next {
+        seen.push(flows[e].to);
+        if let Some(mut route) = route_to(flows, flows[e].to, token_out, seen) {
+            route.insert(0, e);
+            return Some(route);
+        }
+        seen.pop();
+    }
+    None
+}
+
 fn main() {
     // Create instances of UniV2Pool
     let tokens = vec![
@@ -546,12 +1179,23 @@ fn main() {
         },
     ];
 
-    print!("sjvncjsnfvc");
+    // Stable pair next to the constant product pools
+    let mut cfmms: Vec<Cfmm> = pools.iter().cloned().map(Cfmm::from).collect();
+    cfmms.push(Cfmm::ConstantSum(ConstantSumPool {
+        address: Address::from([0x20; 20]),
+        token0: tokens[2],
+        token1: tokens[4],
+        reserve0: U256::from(800),
+        reserve1: U256::from(600),
+        router_fee: U256::from(4),
+        price: 1.0,
+        max_out: None,
+    }));
 
     // Create the PoolGraph from the pool instances
-    let pool_graph = PoolGraph::new(&pools);
+    let pool_graph = PoolGraph::from_cfmms(&cfmms);
     let cycles: Vec<Vec<ethers::types::H160>> = pool_graph.detect_cycles(tokens[4]);
-    let mut cycle_pools: Vec<Vec<UniV2Pool>> = Vec::new();
+    let mut cycle_pools: Vec<Vec<Cfmm>> = Vec::new();
 
     for (i, cycle) in cycles.iter().enumerate() {
         println!("\nCycle {}:", i + 1);
@@ -565,7 +1209,101 @@ fn main() {
 
     pool_graph.print_cycle_details(&cycles[8]);
     println!("Found {} potential cycles", cycle_pools.len());
-    let profitable_paths = pool_graph.find_arb(cycle_pools);
+    // 20 gwei, one native token worth 2000 in the numeraire; every token
+    // here is worth one numeraire unit and trades in whole units
+    let mut gas = GasModel {
+        min_net_profit: 1.0,
+        ..GasModel::new(20.0, 2000.0)
+    };
+    for token in &tokens {
+        gas = gas.with_token(*token, TokenUnits::new(1.0, 0));
+    }
+    let selection = CycleSelection {
+        gas,
+        ..CycleSelection::default()
+    };
+    // Same model as find_arb solves, for running it through another solver
+    let milp = pool_graph.cycle_milp(&cycle_pools, &selection);
+    match write_milp_lp(&milp, "find_arb.lp") {
+        Ok(()) => println!("Cycle MILP written to find_arb.lp"),
+        Err(e) => println!("Could not write find_arb.lp: {}", e),
+    }
+    let profitable_paths = pool_graph.find_arb(cycle_pools.clone(), &selection);
+
+    // Cycles chained on the pools they share
+    let joint = pool_graph.optimize_joint(&cycle_pools, &selection);
+    println!("\nJoint plan, profit {}:", joint.profit);
+    for (k, step) in joint.steps.iter().enumerate() {
+        println!(
+            "  Step {}: cycle {}, input {}, output {}",
+            k + 1,
+            step.cycle,
+            step.input,
+            step.output
+        );
+    }
+
+    // Selling 100 of token 0 for token 3, split over the whole graph
+    match pool_graph.quote_swap(
+        tokens[0],
+        tokens[3],
+        100.0,
+        &SolverConfig::default(),
+        &Slippage::default(),
+    ) {
+        Ok(quote) => {
+            println!(
+                "\nSwap 100 {} -> {}: {}",
+                tokens[0], tokens[3], quote.amount_out
+            );
+            for (k, path) in quote.paths.iter().enumerate() {
+                println!(
+                    "  Path {}: {:?}, {} in, {} out",
+                    k + 1,
+                    path.tokens,
+                    path.amount_in,
+                    path.amount_out
+                );
+            }
+            print!("{}", quote.plan);
+        }
+        Err(e) => println!("Swap quote failed: {}", e),
+    }
+
+    // Buying exactly 50 of token 3 through 0 -> 1 -> 2 -> 3
+    let buy = &cfmms[..3];
+    println!(
+        "\nBuy 50 {}: {} of {} in floats",
+        tokens[3],
+        path_amount_in(buy, tokens[0], 50.0),
+        tokens[0]
+    );
+    match path_amounts_in(buy, tokens[0], U256::from(50)) {
+        Some(amounts) => println!("  Amounts in token units: {:?}", amounts),
+        None => println!("  The path cannot pay 50"),
+    }
+    println!(
+        "  Same path as Pools: {}",
+        Pools::new(pools[..3].to_vec()).calculate_input(50.0)
+    );
+
+    // The 0 -> 1 -> 2 -> 3 -> 4 -> 5 -> 0 loop on borrowed capital
+    let path = Pools::new(pools[..6].to_vec());
+    println!(
+        "Loop profit for 100 after gas: {}",
+        path.net_profit(100.0, &selection.gas)
+    );
+    for (name, loan) in [
+        ("Aave", FlashLoan::aave()),
+        ("Balancer", FlashLoan::balancer()),
+        ("UniV2 flash swap", FlashLoan::uniswap_v2()),
+    ] {
+        let arb = path.flash_loan(&loan);
+        println!(
+            "Flash loan from {}: borrow {}, output {}, repay {}, profit {}",
+            name, arb.borrowed, arb.output, arb.repaid, arb.profit
+        );
+    }
 
     println!("cycle which give profit is {:?}", cycles[8]);
 
@@ -579,3 +1317,244 @@ fn main() {
         PoolGraph::print_profitable_paths(&profitable_paths, tokens[4]);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use CFMM_covex_optimization::solver::Backend;
+
+    fn token(i: u8) -> Address {
+        Address::from([i; 20])
+    }
+
+    fn pair(address: u8, token0: Address, token1: Address, reserves: (u64, u64)) -> UniV2Pool {
+        UniV2Pool::new(
+            Address::from([address; 20]),
+            token0,
+            token1,
+            U256::from(reserves.0),
+            U256::from(reserves.1),
+        )
+    }
+
+    fn config() -> SolverConfig {
+        SolverConfig::from_env_or(Backend::Clarabel).unwrap()
+    }
+
+    // Two cycles from token 0, both through the underpriced 0/1 pool; the
+    // second comes back through slightly thinner liquidity
+    fn shared_cycles() -> (Vec<UniV2Pool>, Vec<Vec<Cfmm>>) {
+        let pools = vec![
+            pair(0x10, token(0), token(1), (1_000_000_000, 2_000_000_000)),
+            pair(0x11, token(1), token(2), (1_000_000_000, 1_000_000_000)),
+            pair(0x12, token(2), token(0), (1_000_000_000, 1_000_000_000)),
+            pair(0x13, token(1), token(3), (1_000_000_000, 900_000_000)),
+            pair(0x14, token(3), token(0), (1_000_000_000, 1_000_000_000)),
+        ];
+        let cycle = |path: [usize; 3]| path.map(|k| Cfmm::from(pools[k].clone())).to_vec();
+        let cycles = vec![cycle([0, 1, 2]), cycle([0, 3, 4])];
+        (pools, cycles)
+    }
+
+    #[test]
+    fn swap_splits_across_parallel_pools() {
+        let pools = [
+            pair(0x10, token(0), token(1), (1_000_000_000, 2_000_000_000)),
+            pair(0x11, token(0), token(1), (500_000_000, 1_000_000_000)),
+        ];
+        let amount = 100_000_000.0;
+        let graph = PoolGraph::new(&pools);
+        assert_eq!(graph.pools().len(), 2);
+
+        let quote = graph
+            .quote_swap(token(0), token(1), amount, &config(), &Slippage::default())
+            .unwrap();
+        let used: HashSet<Address> = quote.plan.hops.iter().map(|hop| hop.pool).collect();
+        assert_eq!(used, HashSet::from([pools[0].address, pools[1].address]));
+
+        // the rounded split, even at its minimum, beats either pool alone
+        let end_min = quote.plan.end_min[&token(1)].as_u128() as f64;
+        for pool in &pools {
+            let alone = pool.get_amount_out(token(0), amount);
+            assert!(quote.amount_out > alone);
+            assert!(end_min > alone);
+        }
+    }
+
+    #[test]
+    fn cycle_milp_gives_a_shared_pool_to_one_cycle() {
+        let (pools, cycles) = shared_cycles();
+        let selection = CycleSelection {
+            budget: 1_000_000.0,
+            max_input: 100_000_000.0,
+            ..CycleSelection::default()
+        };
+        let n = cycles.len();
+        let values = PoolGraph::new(&pools)
+            .cycle_milp(&cycles, &selection)
+            .solve()
+            .unwrap();
+
+        let selected: Vec<usize> = (0..n).filter(|&i| values[n + i] > 0.5).collect();
+        assert_eq!(selected.len(), 1);
+        // the other cycle gets nothing through the link row
+        assert!(values[1 - selected[0]] < 1e-6);
+
+        // both cycles pay far past the budget, so it all goes in
+        let spent: f64 = values[..n].iter().sum();
+        assert!((spent - selection.budget).abs() <= 1e-6 * selection.budget);
+    }
+
+    #[test]
+    fn cycle_chords_stay_below_the_exact_output() {
+        let (pools, cycles) = shared_cycles();
+        let selection = CycleSelection {
+            max_input: 100_000_000.0,
+            ..CycleSelection::default()
+        };
+        let cycle = &cycles[..1];
+        let milp = PoolGraph::new(&pools).cycle_milp(cycle, &selection);
+
+        // output - slope * input - intercept * select <= 0, columns 0, 1, 2
+        let coefficient = |terms: &[(usize, f64)], column: usize| {
+            terms
+                .iter()
+                .find(|(j, _)| *j == column)
+                .map_or(0.0, |(_, c)| -c)
+        };
+        let chords: Vec<(f64, f64)> = milp
+            .rows
+            .iter()
+            .filter(|row| row.name.starts_with("curve_0_"))
+            .map(|row| (coefficient(&row.terms, 0), coefficient(&row.terms, 1)))
+            .collect();
+        assert_eq!(chords.len(), selection.breakpoints);
+
+        // with the cycle selected, the MILP pays the lowest chord
+        let start = cycle_start_token(&cycle[0]).unwrap();
+        let curve = path_curve(&cycle[0], start, selection.max_input, selection.breakpoints);
+        for pair in curve.windows(2) {
+            for input in [pair[0].0, (pair[0].0 + pair[1].0) / 2.0, pair[1].0] {
+                let exact = simulate_path(&cycle[0], start, input).unwrap();
+                let envelope = chords
+                    .iter()
+                    .map(|(slope, intercept)| slope * input + intercept)
+                    .fold(f64::INFINITY, f64::min);
+                assert!(envelope <= exact + 1e-9 * exact.max(1.0));
+            }
+        }
+    }
+
+    // Best profit of `cycle` on its own, executed in token units
+    fn standalone_profit(cycle: &[Cfmm], max_input: f64) -> f64 {
+        let start = cycle_start_token(cycle).unwrap();
+        let amount_in = floor_units(best_input(cycle, start, max_input));
+        let amount_out = execute_path(&mut cycle.to_vec(), start, amount_in).unwrap();
+        amount_out.as_u128() as f64 - amount_in.as_u128() as f64
+    }
+
+    #[test]
+    fn joint_plan_replays_on_shared_pools() {
+        let (pools, cycles) = shared_cycles();
+        let selection = CycleSelection {
+            budget: 1_000_000_000.0,
+            max_input: 1_000_000_000.0,
+            ..CycleSelection::default()
+        };
+        let graph = PoolGraph::new(&pools);
+        let plan = graph.optimize_joint(&cycles, &selection);
+        assert!(!plan.steps.is_empty());
+
+        // each step, executed on the reserves the steps before it left,
+        // pays exactly what the plan says
+        let mut state: HashMap<Address, Cfmm> = pools
+            .iter()
+            .map(|pool| (pool.address, Cfmm::from(pool.clone())))
+            .collect();
+        let mut profit = 0.0;
+        for step in &plan.steps {
+            let cycle = &cycles[step.cycle];
+            let start = cycle_start_token(cycle).unwrap();
+            let mut path: Vec<Cfmm> = cycle
+                .iter()
+                .map(|pool| state[&pool.address()].clone())
+                .collect();
+            let amount_out =
+                execute_path(&mut path, start, U256::from(step.input as u128)).unwrap();
+            assert_eq!(amount_out.as_u128() as f64, step.output);
+            profit += step.output - step.input;
+            for pool in path {
+                state.insert(pool.address(), pool);
+            }
+        }
+        assert_eq!(profit, plan.profit);
+
+        // the shared pool's liquidity is only counted once
+        let alone: Vec<f64> = cycles
+            .iter()
+            .map(|cycle| standalone_profit(cycle, selection.max_input))
+            .collect();
+        assert!(plan.profit >= alone[0].max(alone[1]));
+        assert!(plan.profit < alone[0] + alone[1]);
+
+        // searching only the best cycle on its own leaves the other out
+        let narrow = graph.optimize_joint(
+            &cycles,
+            &CycleSelection {
+                max_candidates: 1,
+                ..selection.clone()
+            },
+        );
+        assert!(alone[0] > alone[1]);
+        assert!(!narrow.steps.is_empty());
+        assert!(narrow.steps.iter().all(|step| step.cycle == 0));
+        assert!(narrow.profit <= plan.profit);
+    }
+
+    // token 0 through 1 and 2 back to 0, each pool traded token0 to token1;
+    // the first pool pays `premium` of token 1 per token 0 before its fee
+    fn flash_path(premium: f64) -> Pools {
+        let reserve1 = (1_000_000_000.0 * premium) as u64;
+        Pools::new(vec![
+            pair(0x10, token(0), token(1), (1_000_000_000, reserve1)),
+            pair(0x11, token(1), token(2), (1_000_000_000, 1_000_000_000)),
+            pair(0x12, token(2), token(0), (1_000_000_000, 1_000_000_000)),
+        ])
+    }
+
+    #[test]
+    fn flash_loan_stops_where_the_cycle_rate_meets_the_fee() {
+        let path = flash_path(2.0);
+        for loan in [
+            FlashLoan::aave(),
+            FlashLoan::balancer(),
+            FlashLoan::uniswap_v2(),
+        ] {
+            let arb = path.flash_loan(&loan);
+            assert!(arb.borrowed > 0.0 && arb.profit > 0.0);
+
+            // marginal rate of the cycle at the loan, by central difference
+            let step = 1e-4 * arb.borrowed;
+            let rate = (path.calculate_profit(arb.borrowed + step)
+                - path.calculate_profit(arb.borrowed - step))
+                / (2.0 * step);
+            assert!((rate - (1.0 + loan.fee)).abs() <= 1e-6);
+            assert!(arb.profit >= path.flash_loan_profit(0.99 * arb.borrowed, &loan));
+            assert!(arb.profit >= path.flash_loan_profit(1.01 * arb.borrowed, &loan));
+        }
+    }
+
+    #[test]
+    fn flash_loan_is_declined_below_the_fee() {
+        // 1.0105 fee^3 is about 1.0014: above par, below 1 + the UniV2 fee
+        let path = flash_path(1.0105);
+        assert!(path.calculate_profit(1.0) > 1.0);
+
+        let arb = path.flash_loan(&FlashLoan::uniswap_v2());
+        assert_eq!(arb.borrowed, 0.0);
+        assert_eq!(arb.profit, 0.0);
+
+        // a free loan still takes the same cycle
+        assert!(path.flash_loan(&FlashLoan::balancer()).profit > 0.0);
+    }
+}
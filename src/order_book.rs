@@ -0,0 +1,244 @@
+use ethers::types::Address;
+use ethers::types::U256;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Result;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceLevel {
+    // quote token paid per base token
+    pub price: f64,
+    // size in base token
+    pub size: f64,
+}
+
+/// Limit-order book (or a set of RFQ quotes) snapshotted from an off-chain
+/// venue, traded as a pseudo-CFMM. Selling base walks down the bids, buying
+/// base walks up the asks, so both output curves are concave and piecewise
+/// linear.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OrderBook {
+    // venue identifier, plays the role of the pool address
+    pub address: Address,
+
+    pub base: Address,
+    pub quote: Address,
+
+    pub bids: Vec<PriceLevel>,
+    pub asks: Vec<PriceLevel>,
+
+    // taker fee, charged on the output
+    #[serde(default)]
+    pub router_fee: U256,
+}
+
+impl OrderBook {
+    pub fn load_from_file(file_path: &str) -> Result<OrderBook> {
+        let file = File::open(file_path)?;
+        let reader = std::io::BufReader::new(file);
+        let mut book: OrderBook = serde_json::from_reader(reader)?;
+        book.sort_levels();
+        Ok(book)
+    }
+
+    /// Best bid first and best ask first, dropping empty levels and levels
+    /// with a non-finite price or size.
+    pub fn sort_levels(&mut self) {
+        let usable = |level: &PriceLevel| {
+            level.size.is_finite()
+                && level.price.is_finite()
+                && level.size > 0.0
+                && level.price > 0.0
+        };
+        self.bids.retain(usable);
+        self.asks.retain(usable);
+        self.bids.sort_by(|a, b| b.price.total_cmp(&a.price));
+        self.asks.sort_by(|a, b| a.price.total_cmp(&b.price));
+    }
+
+    pub fn fee(&self) -> f64 {
+        1.0 - (self.router_fee.as_u64() as f64 / 10000.0)
+    }
+
+    /// Total base that can be bought from the asks.
+    pub fn ask_depth(&self) -> f64 {
+        self.asks.iter().map(|level| level.size).sum()
+    }
+
+    /// Total quote that can be received by selling into the bids.
+    pub fn bid_depth(&self) -> f64 {
+        self.bids.iter().map(|level| level.size * level.price).sum()
+    }
+
+    /// Largest input of `token_in` the book can fill: all bid sizes when
+    /// selling base, what all the asks cost when buying it.
+    pub fn max_amount_in(&self, token_in: Address) -> f64 {
+        if token_in == self.base {
+            self.bids.iter().map(|level| level.size).sum()
+        } else {
+            self.asks.iter().map(|level| level.size * level.price).sum()
+        }
+    }
+
+    /// Output for `amount_in`, walking the levels best first; `None` when the
+    /// input runs past the end of the book, which a fill-or-kill taker order
+    /// rejects rather than filling part of.
+    pub fn get_amount_out(&self, token_in: Address, amount_in: f64) -> Option<f64> {
+        let mut remaining = amount_in;
+        let mut amount_out = 0.0;
+
+        if token_in == self.base {
+            for level in &self.bids {
+                let fill = remaining.min(level.size);
+                amount_out += fill * level.price;
+                remaining -= fill;
+                if remaining <= 0.0 {
+                    break;
+                }
+            }
+        } else {
+            for level in &self.asks {
+                let fill = remaining.min(level.size * level.price);
+                amount_out += fill / level.price;
+                remaining -= fill;
+                if remaining <= 0.0 {
+                    break;
+                }
+            }
+        }
+
+        // float round-off on the last level still fills
+        if remaining > amount_in * 1e-12 {
+            return None;
+        }
+        Some(amount_out * self.fee())
+    }
+
+    /// Input of `token_in` that buys `amount_out` after the fee, walking the
+    /// same levels as `get_amount_out`; infinite past the end of the book.
+    pub fn get_amount_in(&self, token_in: Address, amount_out: f64) -> f64 {
+        let mut remaining = amount_out / self.fee();
+        let mut amount_in = 0.0;
+
+        if token_in == self.base {
+            for level in &self.bids {
+                if remaining <= 0.0 {
+                    break;
+                }
+                let fill = remaining.min(level.size * level.price);
+                amount_in += fill / level.price;
+                remaining -= fill;
+            }
+        } else {
+            for level in &self.asks {
+                if remaining <= 0.0 {
+                    break;
+                }
+                let fill = remaining.min(level.size);
+                amount_in += fill * level.price;
+                remaining -= fill;
+            }
+        }
+
+        if remaining > 0.0 {
+            f64::INFINITY
+        } else {
+            amount_in
+        }
+    }
+
+    /// Takes the levels a trade of `amount_in` walks through out of the book.
+    pub fn fill(&mut self, token_in: Address, amount_in: f64) {
+        let mut remaining = amount_in;
+
+        if token_in == self.base {
+            for level in self.bids.iter_mut() {
+                let fill = remaining.min(level.size);
+                level.size -= fill;
+                remaining -= fill;
+                if remaining <= 0.0 {
+                    break;
+                }
+            }
+        } else {
+            for level in self.asks.iter_mut() {
+                let fill = remaining.min(level.size * level.price);
+                level.size -= fill / level.price;
+                remaining -= fill;
+                if remaining <= 0.0 {
+                    break;
+                }
+            }
+        }
+
+        self.sort_levels();
+    }
+
+    /// Trading set in the router's `[lambda0, delta0, lambda1, delta1]`
+    /// variables with token0 = base and token1 = quote, in the same
+    /// `a . x <= b` row form as `constant_sum_trading_set`.
+    ///
+    /// A concave piecewise-linear curve is the minimum of its segments, so one
+    /// cut per level describes it exactly; a depth row stops the input at the
+    /// end of the book.
+    pub fn trading_set(&self) -> Vec<([f64; 4], f64)> {
+        let fee = self.fee();
+        let mut rows = Vec::new();
+
+        // Selling base: lambda1 <= fee * (quote so far + price * (delta0 - base so far))
+        let mut base_so_far = 0.0;
+        let mut quote_so_far = 0.0;
+        for level in &self.bids {
+            rows.push((
+                [0.0, -fee * level.price, 1.0, 0.0],
+                fee * (quote_so_far - level.price * base_so_far),
+            ));
+            base_so_far += level.size;
+            quote_so_far += level.size * level.price;
+        }
+        rows.push(([0.0, 1.0, 0.0, 0.0], base_so_far));
+        if self.bids.is_empty() {
+            rows.push(([0.0, 0.0, 1.0, 0.0], 0.0));
+        }
+
+        // Buying base: lambda0 <= fee * (base so far + (delta1 - quote so far) / price)
+        let mut base_so_far = 0.0;
+        let mut quote_so_far = 0.0;
+        for level in &self.asks {
+            rows.push((
+                [1.0, 0.0, 0.0, -fee / level.price],
+                fee * (base_so_far - quote_so_far / level.price),
+            ));
+            base_so_far += level.size;
+            quote_so_far += level.size * level.price;
+        }
+        rows.push(([0.0, 0.0, 0.0, 1.0], quote_so_far));
+        if self.asks.is_empty() {
+            rows.push(([1.0, 0.0, 0.0, 0.0], 0.0));
+        }
+
+        rows
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_levels_drops_non_finite_levels() {
+        let level = |price, size| PriceLevel { price, size };
+        let mut book = OrderBook {
+            address: Address::from([0x12; 20]),
+            base: Address::from([0; 20]),
+            quote: Address::from([1; 20]),
+            bids: vec![level(1.9, 10.0), level(f64::NAN, 5.0), level(2.0, 10.0)],
+            asks: vec![level(2.2, f64::INFINITY), level(2.1, 10.0), level(0.0, 1.0)],
+            router_fee: U256::zero(),
+        };
+        book.sort_levels();
+        let prices = |levels: &[PriceLevel]| levels.iter().map(|l| l.price).collect::<Vec<_>>();
+        assert_eq!(prices(&book.bids), vec![2.0, 1.9]);
+        assert_eq!(prices(&book.asks), vec![2.1]);
+    }
+}
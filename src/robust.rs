@@ -0,0 +1,369 @@
+use ethers::types::Address;
+use std::fmt;
+
+use crate::cfmm::Cfmm;
+use crate::router::{Layout, PoolTrade, Router};
+use crate::solver::{solve, ConicProblem, Solution, SolverConfig, SolverError};
+use crate::verify::{verify, Verification};
+
+// Weight of the expected profit next to the worst case, so scenarios off the
+// worst case still take everything their pools pay.
+const TIE_BREAK: f64 = 1e-6;
+
+/// What the robust router maximises over its reserve scenarios.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RobustObjective {
+    // profit of the worst scenario
+    WorstCase,
+    // mean profit, every scenario equally likely
+    Expected,
+}
+
+/// Scenarios for reserves known to within `band` (0.01 for ±1%): the pools
+/// as they are, every reserve `band` lower, and per router token one where
+/// its reserves are `band` higher and all others `band` lower, i.e. the
+/// token cheapening against the rest.
+pub fn band_scenarios(router: &Router, band: f64) -> Vec<Vec<Cfmm>> {
+    let shift = |factor: &dyn Fn(Address) -> f64| -> Vec<Cfmm> {
+        router
+            .pools
+            .iter()
+            .map(|pool| pool.scale_reserves(factor))
+            .collect()
+    };
+
+    let mut scenarios = vec![router.pools.clone(), shift(&|_| 1.0 - band)];
+    for &cheap in &router.tokens {
+        scenarios.push(shift(&|token| {
+            if token == cheap {
+                1.0 + band
+            } else {
+                1.0 - band
+            }
+        }));
+    }
+    scenarios
+}
+
+/// One scenario of a robust solution.
+#[derive(Debug, Clone)]
+pub struct ScenarioOutcome {
+    // what the pools pay for the shared inputs on this scenario's reserves
+    pub trades: Vec<PoolTrade>,
+    // net amount per router token
+    pub net: Vec<f64>,
+    // market value of `net`
+    pub profit: f64,
+    pub verification: Verification,
+}
+
+/// Inputs chosen once for all scenarios, and what they earn in each.
+#[derive(Debug, Clone)]
+pub struct RobustSolution {
+    // per pool and pool token, what is paid in; the same in every scenario
+    pub tendered: Vec<Vec<f64>>,
+    pub scenarios: Vec<ScenarioOutcome>,
+    pub raw: Solution,
+}
+
+impl RobustSolution {
+    pub fn profits(&self) -> Vec<f64> {
+        self.scenarios.iter().map(|s| s.profit).collect()
+    }
+
+    pub fn worst(&self) -> f64 {
+        self.profits().into_iter().fold(f64::INFINITY, f64::min)
+    }
+
+    pub fn best(&self) -> f64 {
+        self.profits().into_iter().fold(f64::NEG_INFINITY, f64::max)
+    }
+
+    pub fn expected(&self) -> f64 {
+        self.profits().iter().sum::<f64>() / self.scenarios.len() as f64
+    }
+}
+
+impl fmt::Display for RobustSolution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (s, scenario) in self.scenarios.iter().enumerate() {
+            writeln!(f, "Scenario {}: profit {:.6}", s, scenario.profit)?;
+        }
+        writeln!(
+            f,
+            "Profit worst {:.6}, expected {:.6}, best {:.6}",
+            self.worst(),
+            self.expected(),
+            self.best()
+        )
+    }
+}
+
+impl Router {
+    /// One copy of the routing problem per scenario, each on that scenario's
+    /// reserves, with what is tendered to every pool tied across the copies:
+    /// the inputs are sent before the reserves are known and each scenario
+    /// only decides what comes back. Scenarios must hold the router's pools,
+    /// in order, with other reserves.
+    pub fn build_robust(
+        &self,
+        scenarios: &[Vec<Cfmm>],
+        objective: RobustObjective,
+        tolerance: f64,
+    ) -> (ConicProblem, Vec<Layout>) {
+        assert!(!scenarios.is_empty(), "no reserve scenarios");
+        for pools in scenarios {
+            assert!(
+                pools.len() == self.pools.len()
+                    && pools
+                        .iter()
+                        .zip(&self.pools)
+                        .all(|(a, b)| a.address() == b.address() && a.tokens() == b.tokens()),
+                "scenario pools differ from the router's"
+            );
+        }
+
+        let per = self.n_vars();
+        let count = scenarios.len() as f64;
+        // the worst-case profit, after every scenario's variables
+        let worst = scenarios.len() * per;
+        let n_vars = match objective {
+            RobustObjective::WorstCase => worst + 1,
+            RobustObjective::Expected => worst,
+        };
+        let mut prob = ConicProblem::new(n_vars);
+
+        let layouts: Vec<Layout> = scenarios
+            .iter()
+            .enumerate()
+            .map(|(s, pools)| {
+                self.add_routing(
+                    &mut prob,
+                    pools,
+                    s * per,
+                    tolerance,
+                    &format!("scenario {}: ", s),
+                )
+            })
+            .collect();
+
+        // delta of every scenario = delta of the first
+        for (s, layout) in layouts.iter().enumerate().skip(1) {
+            for (i, pool) in self.pools.iter().enumerate() {
+                for (k, token) in pool.tokens().iter().enumerate() {
+                    prob.add_eq(
+                        vec![
+                            (layouts[0].offsets[i] + 2 * k + 1, -1.0),
+                            (layout.offsets[i] + 2 * k + 1, 1.0),
+                        ],
+                        0.0,
+                    );
+                    prob.label_from(
+                        prob.cones.len() - 1,
+                        &format!(
+                            "scenario {}: pool {:?} tendered {:?} as planned",
+                            s,
+                            pool.address(),
+                            token
+                        ),
+                    );
+                }
+            }
+        }
+
+        let expected_weight = match objective {
+            RobustObjective::WorstCase => {
+                prob.c[worst] = -1.0;
+                // worst <= prices . net_s
+                for (s, layout) in layouts.iter().enumerate() {
+                    let mut a = vec![(worst, 1.0)];
+                    for (t, price) in self.prices.iter().enumerate() {
+                        a.push((layout.net + t, -price));
+                    }
+                    prob.add_leq(a, 0.0);
+                    prob.label_from(
+                        prob.cones.len() - 1,
+                        &format!("scenario {}: worst-case profit", s),
+                    );
+                }
+                TIE_BREAK / count
+            }
+            RobustObjective::Expected => 1.0 / count,
+        };
+        for layout in &layouts {
+            for (t, price) in self.prices.iter().enumerate() {
+                prob.c[layout.net + t] -= expected_weight * price;
+            }
+        }
+
+        (prob, layouts)
+    }
+
+    /// Inputs that maximise the worst-case or expected profit over
+    /// `scenarios` (see `band_scenarios`), with the profit each scenario
+    /// makes. Every scenario's trades are replayed against its own pools; a
+    /// solution leaving any of them by more than `config.verify_slack()` is
+    /// refused.
+    pub fn solve_robust(
+        &self,
+        scenarios: &[Vec<Cfmm>],
+        objective: RobustObjective,
+        config: &SolverConfig,
+    ) -> Result<RobustSolution, SolverError> {
+        let (prob, layouts) = self.build_robust(scenarios, objective, config.tolerance);
+        let solution = solve(&prob, config)?;
+        let prices = self.price_map();
+
+        let mut outcomes = Vec::with_capacity(scenarios.len());
+        for (pools, layout) in scenarios.iter().zip(&layouts) {
+            let (trades, net) = self.trades_at(layout, &solution.x);
+            let profit = net.iter().zip(&self.prices).map(|(n, p)| n * p).sum();
+            let verification = verify(pools, &trades, &prices, profit);
+            if !verification.accepts(config.verify_slack()) {
+                return Err(SolverError::Unverified(verification));
+            }
+            outcomes.push(ScenarioOutcome {
+                trades,
+                net,
+                profit,
+                verification,
+            });
+        }
+
+        Ok(RobustSolution {
+            tendered: outcomes[0]
+                .trades
+                .iter()
+                .map(|trade| trade.tendered.clone())
+                .collect(),
+            scenarios: outcomes,
+            raw: solution,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfmm::UniV2Pool;
+    use crate::solver::{Backend, Cone};
+    use std::collections::HashMap;
+
+    fn router() -> Router {
+        let tokens: Vec<Address> = (0..3u8).map(|i| Address::from([i; 20])).collect();
+        let pools = vec![
+            UniV2Pool::fixture(0x10, tokens[0], tokens[1], (1000, 2000)),
+            UniV2Pool::fixture(0x11, tokens[1], tokens[2], (2000, 1100)),
+        ];
+        let prices = HashMap::from([(tokens[0], 1.0), (tokens[1], 0.5), (tokens[2], 1.0)]);
+        Router::new(pools, &prices)
+    }
+
+    // first row of every cone block
+    fn block_rows(prob: &ConicProblem) -> Vec<usize> {
+        prob.cones
+            .iter()
+            .scan(0, |row, cone| {
+                let first = *row;
+                *row += cone.dim();
+                Some(first)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tendered_amounts_are_tied_across_scenarios() {
+        let router = router();
+        let scenarios = band_scenarios(&router, 0.01);
+        assert_eq!(scenarios.len(), 2 + router.tokens.len());
+
+        let (prob, layouts) = router.build_robust(&scenarios, RobustObjective::Expected, 0.0);
+        assert_eq!(prob.n_vars, scenarios.len() * router.n_vars());
+
+        let rows = block_rows(&prob);
+        let mut ties = 0;
+        for (s, layout) in layouts.iter().enumerate().skip(1) {
+            for (i, pool) in router.pools.iter().enumerate() {
+                for (k, token) in pool.tokens().iter().enumerate() {
+                    let label = format!(
+                        "scenario {}: pool {:?} tendered {:?} as planned",
+                        s,
+                        pool.address(),
+                        token
+                    );
+                    let block = prob.labels.iter().position(|l| *l == label).unwrap();
+                    let row = rows[block];
+                    assert_eq!(prob.cones[block], Cone::Zero(1));
+                    assert_eq!(prob.b[row], 0.0);
+                    assert_eq!(prob.a[row].len(), 2);
+                    assert_eq!(prob.coef(row, layouts[0].offsets[i] + 2 * k + 1), -1.0);
+                    assert_eq!(prob.coef(row, layout.offsets[i] + 2 * k + 1), 1.0);
+                    ties += 1;
+                }
+            }
+        }
+        // received amounts are left free per scenario
+        let per_scenario: usize = router.pools.iter().map(|p| p.tokens().len()).sum();
+        assert_eq!(ties, (scenarios.len() - 1) * per_scenario);
+        assert_eq!(
+            prob.labels
+                .iter()
+                .filter(|l| l.ends_with("as planned"))
+                .count(),
+            ties
+        );
+    }
+
+    #[test]
+    fn worst_case_is_bounded_by_every_scenario() {
+        let router = router();
+        let scenarios = band_scenarios(&router, 0.01);
+        let (prob, layouts) = router.build_robust(&scenarios, RobustObjective::WorstCase, 0.0);
+        let worst = scenarios.len() * router.n_vars();
+        assert_eq!(prob.n_vars, worst + 1);
+        assert_eq!(prob.c[worst], -1.0);
+
+        let rows = block_rows(&prob);
+        for (s, layout) in layouts.iter().enumerate() {
+            let label = format!("scenario {}: worst-case profit", s);
+            let block = prob.labels.iter().position(|l| *l == label).unwrap();
+            let row = rows[block];
+            assert_eq!(prob.coef(row, worst), 1.0);
+            for (t, price) in router.prices.iter().enumerate() {
+                assert_eq!(prob.coef(row, layout.net + t), -price);
+            }
+        }
+    }
+
+    #[test]
+    fn worst_case_solution_holds_up_in_every_scenario() {
+        let router = router();
+        let scenarios = band_scenarios(&router, 0.05);
+        let config = SolverConfig::from_env_or(Backend::Clarabel).unwrap();
+        let worst_case = router
+            .solve_robust(&scenarios, RobustObjective::WorstCase, &config)
+            .unwrap();
+        let expected = router
+            .solve_robust(&scenarios, RobustObjective::Expected, &config)
+            .unwrap();
+
+        // amounts are accurate to eps in scaled units
+        let accuracy = config.eps * router.scale();
+        assert!(worst_case.worst() >= expected.worst() - accuracy);
+        assert!(
+            expected.profits().iter().sum::<f64>()
+                >= worst_case.profits().iter().sum::<f64>() - accuracy
+        );
+
+        for solution in [&worst_case, &expected] {
+            for scenario in &solution.scenarios {
+                assert!(scenario.verification.accepts(config.verify_slack()));
+                for (trade, tendered) in scenario.trades.iter().zip(&solution.tendered) {
+                    for (amount, planned) in trade.tendered.iter().zip(tendered) {
+                        assert!((amount - planned).abs() <= accuracy);
+                    }
+                }
+            }
+        }
+    }
+}
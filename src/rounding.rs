@@ -0,0 +1,385 @@
+use ethers::types::{Address, U256};
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cfmm::{floor_units, Cfmm};
+use crate::router::PoolTrade;
+
+/// Smallest amount, in token units, a leg of a pool trade must move to count:
+/// less than a unit cannot be sent.
+pub const DUST: f64 = 1.0;
+
+/// Fraction of a pool trade's largest leg below which its other legs are
+/// solver noise: an interior-point backend leaves every unused leg slightly
+/// above zero, in proportion to the amounts traded.
+pub const NOISE: f64 = 1e-4;
+
+/// One swap of an executable plan, in token units.
+#[derive(Debug, Clone)]
+pub struct Hop {
+    pub pool: Address,
+    pub token_in: Address,
+    pub token_out: Address,
+    pub amount_in: U256,
+    // output of the exact re-simulation, after the hops before this one
+    pub amount_out: U256,
+    // amountOutMin to send with the swap, see `Slippage`
+    pub amount_out_min: U256,
+}
+
+/// How far a plan may drift between simulation and inclusion before its
+/// swaps revert.
+#[derive(Debug, Clone, Copy)]
+pub struct Slippage {
+    // fraction given up below the worst-case output, e.g. 0.005
+    pub tolerance: f64,
+    // fraction by which each pool's state may move against the trade
+    // (`Cfmm::shifted_against`)
+    pub reserve_shift: f64,
+}
+
+impl Default for Slippage {
+    fn default() -> Self {
+        Slippage {
+            tolerance: 0.005,
+            reserve_shift: 0.0,
+        }
+    }
+}
+
+/// Integer swaps in execution order and the wallet balances around them.
+#[derive(Debug, Clone)]
+pub struct ExecutionPlan {
+    pub hops: Vec<Hop>,
+    // balance per token before the first hop
+    pub start: HashMap<Address, U256>,
+    // balance per token after the last hop
+    pub end: HashMap<Address, U256>,
+    // balance per token after the last hop if every hop pays only its
+    // `amount_out_min`: the minimum final output
+    pub end_min: HashMap<Address, U256>,
+}
+
+impl ExecutionPlan {
+    /// End minus start balance per token.
+    pub fn net(&self) -> Vec<(Address, f64)> {
+        let mut tokens: Vec<Address> = self.start.keys().chain(self.end.keys()).cloned().collect();
+        tokens.sort();
+        tokens.dedup();
+        tokens
+            .into_iter()
+            .map(|token| {
+                let start = self.start.get(&token).cloned().unwrap_or_default();
+                let end = self.end.get(&token).cloned().unwrap_or_default();
+                (token, end.as_u128() as f64 - start.as_u128() as f64)
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for ExecutionPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, hop) in self.hops.iter().enumerate() {
+            writeln!(
+                f,
+                "Hop {}: pool {}, {} {} -> {} {} (min {})",
+                i + 1,
+                hop.pool,
+                hop.amount_in,
+                hop.token_in,
+                hop.amount_out,
+                hop.token_out,
+                hop.amount_out_min
+            )?;
+        }
+        for (token, amount) in self.net() {
+            writeln!(f, "Net {}: {}", token, amount)?;
+        }
+        let mut tokens: Vec<&Address> = self.end_min.keys().collect();
+        tokens.sort();
+        for token in tokens {
+            writeln!(f, "Min end balance {}: {}", token, self.end_min[token])?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum RoundingError {
+    // the pool takes or pays several tokens at once, which is no single swap
+    MultiAssetTrade(Address),
+}
+
+impl fmt::Display for RoundingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoundingError::MultiAssetTrade(pool) => {
+                write!(f, "pool {} trades more than one token each way", pool)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RoundingError {}
+
+/// Tokens a pool trade pays in and takes out, as indices into `trade.tokens`,
+/// leaving out legs below `DUST` or below `NOISE` times the largest leg.
+pub fn trade_legs(trade: &PoolTrade) -> (Vec<usize>, Vec<usize>) {
+    let largest = trade
+        .tendered
+        .iter()
+        .chain(&trade.received)
+        .fold(0.0, |largest: f64, &leg| largest.max(leg));
+    let floor = DUST.max(NOISE * largest);
+    let ins = (0..trade.tokens.len())
+        .filter(|&k| trade.tendered[k] >= floor)
+        .collect();
+    let outs = (0..trade.tokens.len())
+        .filter(|&k| trade.received[k] >= floor)
+        .collect();
+    (ins, outs)
+}
+
+// A pool trade read as a swap; amounts still fractional.
+struct PlannedHop {
+    index: usize,
+    token_in: Address,
+    token_out: Address,
+    amount_in: f64,
+    amount_out: f64,
+}
+
+/// Reads each pool trade as one swap and puts them in execution order: a hop
+/// runs once every other hop paying out its input token has run. Inside a
+/// cycle, where that never happens, the earliest pending hop goes first.
+fn plan_hops(trades: &[PoolTrade]) -> Result<Vec<PlannedHop>, RoundingError> {
+    let mut pending = Vec::new();
+    for (index, trade) in trades.iter().enumerate() {
+        let (ins, outs) = trade_legs(trade);
+        // nothing in or nothing out above dust: no trade worth sending
+        if ins.is_empty() || outs.is_empty() {
+            continue;
+        }
+        if ins.len() > 1 || outs.len() > 1 {
+            return Err(RoundingError::MultiAssetTrade(trade.pool));
+        }
+        pending.push(PlannedHop {
+            index,
+            token_in: trade.tokens[ins[0]],
+            token_out: trade.tokens[outs[0]],
+            amount_in: trade.tendered[ins[0]],
+            amount_out: trade.received[outs[0]],
+        });
+    }
+
+    let mut ordered = Vec::with_capacity(pending.len());
+    while !pending.is_empty() {
+        let next = (0..pending.len())
+            .find(|&i| {
+                !pending
+                    .iter()
+                    .enumerate()
+                    .any(|(j, other)| j != i && other.token_out == pending[i].token_in)
+            })
+            .unwrap_or(0);
+        ordered.push(pending.remove(next));
+    }
+
+    Ok(ordered)
+}
+
+/// Smallest wallet balances, in whole units, that let the fractional plan
+/// run in execution order without any balance going negative.
+pub fn required_holdings(trades: &[PoolTrade]) -> Result<HashMap<Address, U256>, RoundingError> {
+    let mut balance: HashMap<Address, f64> = HashMap::new();
+    let mut holdings: HashMap<Address, f64> = HashMap::new();
+
+    for hop in plan_hops(trades)? {
+        let available = balance.entry(hop.token_in).or_insert(0.0);
+        if *available < hop.amount_in {
+            *holdings.entry(hop.token_in).or_insert(0.0) += hop.amount_in - *available;
+            *available = hop.amount_in;
+        }
+        *available -= hop.amount_in;
+        *balance.entry(hop.token_out).or_insert(0.0) += hop.amount_out;
+    }
+
+    Ok(holdings
+        .into_iter()
+        .map(|(token, amount)| (token, U256::from(amount.ceil() as u128)))
+        .collect())
+}
+
+/// Turns a continuous solution into integer swaps: inputs are rounded down,
+/// every hop is re-simulated with the pools' exact math on the state left by
+/// the hops before it, and an input is shrunk to what the pool can fill and
+/// to the wallet's guaranteed balance, the one left if every earlier hop
+/// paid only its `amount_out_min`. No hop can then spend more than the
+/// wallet holds, however the hops before it filled.
+///
+/// Each hop's `amount_out_min` is its output on the pool shifted against it
+/// by `slippage.reserve_shift`, less `slippage.tolerance`, for the input it
+/// is sized to; the hop reverts below it instead of passing a worse state
+/// on.
+///
+/// `trades` are one per entry of `pools`; `holdings` is what the wallet
+/// starts with (see `required_holdings`).
+pub fn round_trades(
+    pools: &[Cfmm],
+    trades: &[PoolTrade],
+    holdings: &HashMap<Address, U256>,
+    slippage: &Slippage,
+) -> Result<ExecutionPlan, RoundingError> {
+    let mut state: Vec<Cfmm> = pools.to_vec();
+    let mut balance = holdings.clone();
+    let mut balance_min = holdings.clone();
+    let mut hops = Vec::new();
+
+    for planned in plan_hops(trades)? {
+        // the expected balance is never below the guaranteed one
+        let available = balance_min
+            .get(&planned.token_in)
+            .cloned()
+            .unwrap_or_default();
+        let pool = &mut state[planned.index];
+        let capacity = pool.max_amount_in_exact(planned.token_in, planned.token_out);
+        let amount_in = floor_units(planned.amount_in).min(available).min(capacity);
+        if amount_in.is_zero() {
+            continue;
+        }
+
+        let amount_out = match pool.swap_exact(planned.token_in, planned.token_out, amount_in) {
+            Some(amount_out) => amount_out,
+            None => continue,
+        };
+        // a shifted pool that can no longer fill the hop guarantees nothing
+        let worst = pool
+            .shifted_against(planned.token_in, planned.token_out, slippage.reserve_shift)
+            .swap_exact(planned.token_in, planned.token_out, amount_in)
+            .unwrap_or_default()
+            .min(amount_out);
+        let amount_out_min = floor_units(worst.as_u128() as f64 * (1.0 - slippage.tolerance));
+        pool.apply_swap(planned.token_in, planned.token_out, amount_in, amount_out);
+
+        *balance.entry(planned.token_in).or_default() -= amount_in;
+        *balance.entry(planned.token_out).or_default() += amount_out;
+        balance_min.insert(planned.token_in, available - amount_in);
+        *balance_min.entry(planned.token_out).or_default() += amount_out_min;
+        hops.push(Hop {
+            pool: pool.address(),
+            token_in: planned.token_in,
+            token_out: planned.token_out,
+            amount_in,
+            amount_out,
+            amount_out_min,
+        });
+    }
+
+    Ok(ExecutionPlan {
+        hops,
+        start: holdings.clone(),
+        end: balance,
+        end_min: balance_min,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cfmm::UniV2Pool;
+
+    fn trade(pool: &Cfmm, tendered: Vec<f64>, received: Vec<f64>) -> PoolTrade {
+        PoolTrade {
+            pool: pool.address(),
+            tokens: pool.tokens(),
+            received,
+            tendered,
+        }
+    }
+
+    #[test]
+    fn solver_noise_on_unused_legs_is_dust() {
+        let tokens: Vec<Address> = (0..3u8).map(|i| Address::from([i; 20])).collect();
+        let pools = vec![
+            UniV2Pool::fixture(0x10, tokens[0], tokens[1], (1_000_000, 2_000_000)),
+            UniV2Pool::fixture(0x11, tokens[1], tokens[2], (2_000_000, 1_000_000)),
+        ];
+        // what an interior-point solver returns: the unused lambda and delta
+        // of each pool are tiny but not zero
+        let trades = vec![
+            trade(&pools[0], vec![1000.0, 1e-12], vec![1e-12, 1990.0]),
+            trade(&pools[1], vec![1990.0, 1e-12], vec![1e-12, 985.0]),
+        ];
+
+        let holdings = required_holdings(&trades).unwrap();
+        assert_eq!(holdings[&tokens[0]], U256::from(1000));
+        assert!(holdings
+            .get(&tokens[1])
+            .is_none_or(|amount| amount.is_zero()));
+
+        let plan = round_trades(&pools, &trades, &holdings, &Slippage::default()).unwrap();
+        assert_eq!(plan.hops.len(), 2);
+        assert_eq!(plan.hops[0].token_in, tokens[0]);
+        assert_eq!(plan.hops[0].token_out, tokens[1]);
+        assert_eq!(plan.hops[1].token_in, tokens[1]);
+        assert_eq!(plan.hops[1].token_out, tokens[2]);
+    }
+
+    #[test]
+    fn downstream_hops_spend_only_the_guaranteed_balance() {
+        let tokens: Vec<Address> = (0..3u8).map(|i| Address::from([i; 20])).collect();
+        let pools = vec![
+            UniV2Pool::fixture(0x10, tokens[0], tokens[1], (1_000_000, 2_000_000)),
+            UniV2Pool::fixture(0x11, tokens[1], tokens[2], (2_000_000, 1_000_000)),
+        ];
+        let trades = vec![
+            trade(&pools[0], vec![1000.0, 0.0], vec![0.0, 1990.0]),
+            trade(&pools[1], vec![1990.0, 0.0], vec![0.0, 985.0]),
+        ];
+        let holdings = HashMap::from([(tokens[0], U256::from(1000))]);
+        let slippage = Slippage {
+            tolerance: 0.01,
+            reserve_shift: 0.01,
+        };
+
+        let plan = round_trades(&pools, &trades, &holdings, &slippage).unwrap();
+        assert_eq!(plan.hops.len(), 2);
+        let (first, second) = (&plan.hops[0], &plan.hops[1]);
+        // the first hop may pay only its minimum, which is all the second spends
+        assert!(first.amount_out_min < first.amount_out);
+        assert_eq!(second.amount_in, first.amount_out_min);
+        assert!(second.amount_out_min <= second.amount_out);
+        assert_eq!(plan.end_min[&tokens[1]], U256::zero());
+        assert_eq!(plan.end_min[&tokens[2]], second.amount_out_min);
+        assert_eq!(plan.end[&tokens[1]], first.amount_out - second.amount_in);
+    }
+
+    #[test]
+    fn legs_far_below_the_largest_are_noise() {
+        let pool = UniV2Pool::fixture(
+            0x10,
+            Address::from([0; 20]),
+            Address::from([1; 20]),
+            (1_000_000_000_000, 2_000_000_000_000),
+        );
+        // 30 units of noise next to a 1e10 trade
+        let noisy = trade(&pool, vec![1e10, 30.0], vec![25.0, 1.98e10]);
+        assert_eq!(trade_legs(&noisy), (vec![0], vec![1]));
+        // the same 30 units are a leg of their own in a small trade
+        let small = trade(&pool, vec![100.0, 30.0], vec![25.0, 190.0]);
+        assert_eq!(trade_legs(&small), (vec![0, 1], vec![0, 1]));
+    }
+
+    #[test]
+    fn legs_below_one_unit_are_dust() {
+        let pool = UniV2Pool::fixture(
+            0x10,
+            Address::from([0; 20]),
+            Address::from([1; 20]),
+            (100, 100),
+        );
+        let (ins, outs) = trade_legs(&trade(&pool, vec![5.0, 0.5], vec![0.9, 4.0]));
+        assert_eq!(ins, vec![0]);
+        assert_eq!(outs, vec![1]);
+    }
+}
@@ -0,0 +1,647 @@
+use ethers::types::Address;
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::cfmm::{BalancerPool, Cfmm, UniV2Pool};
+use crate::diagnose::explain_infeasibility;
+use crate::solver::{
+    solve, Cone, ConicProblem, Session, Solution, SolverConfig, SolverError, SparseRow,
+};
+use crate::verify::{verify, Verification};
+
+/// Convex CFMM router: for every pool `i` and each of its tokens it chooses
+/// `lambda` (received from the pool) and `delta` (tendered to it), keeps each
+/// pool inside its exact trading set and maximises the market value of the
+/// net trade.
+///
+/// Variables are laid out pool by pool as `[lambda_0, delta_0, lambda_1,
+/// delta_1, ...]`, followed by any auxiliary variables the cones need and
+/// one net-trade variable per token. They count `scale()` base units each,
+/// and the objective is the market value over `scale()`.
+pub struct Router {
+    pub tokens: Vec<Address>,
+    pub pools: Vec<Cfmm>,
+    // market value of each entry of `tokens`
+    pub prices: Vec<f64>,
+    // wallet balance of each entry of `tokens`; None leaves capital unlimited
+    pub inventory: Option<Vec<f64>>,
+}
+
+/// Where the parts of a routing problem sit in its variables and rows.
+#[derive(Debug, Clone)]
+pub struct Layout {
+    // first variable of each pool
+    pub offsets: Vec<usize>,
+    // first net-trade variable, one per router token
+    pub net: usize,
+    // trading-set rows of each pool
+    pub pool_rows: Vec<Range<usize>>,
+    // rows defining the net trade, one per router token
+    pub net_rows: Range<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolTrade {
+    pub pool: Address,
+    pub tokens: Vec<Address>,
+    // lambda, what the pool pays out
+    pub received: Vec<f64>,
+    // delta, what is paid into the pool
+    pub tendered: Vec<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct RoutingSolution {
+    pub trades: Vec<PoolTrade>,
+    // net amount per router token, sum of received - tendered
+    pub net: Vec<f64>,
+    // market value of `net`
+    pub value: f64,
+    // dual of the net-trade rows: marginal value of one more unit of each
+    // router token at the optimum. This is `prices` itself unless an
+    // inventory row binds, so it says nothing about where the pools end up.
+    pub token_prices: Vec<f64>,
+    // per pool and pool token, the shadow price its trading set puts on the
+    // token; a pool pays out where this meets the token price and takes in
+    // where price / fee does, an idle pool sits between the two
+    pub pool_prices: Vec<Vec<f64>>,
+    // per pool and pool token, what the pool pays in its first token for one
+    // more unit of the token at the post-trade reserves, fee included (1 for
+    // the first token): the prices the trade leaves behind
+    pub post_trade_rates: Vec<Vec<f64>>,
+    // the trades replayed against the exact pool math
+    pub verification: Verification,
+    // primal and dual the above were read from, a warm start for the next block
+    pub raw: Solution,
+}
+
+impl Router {
+    /// Tokens are collected from the pools in order of appearance; tokens
+    /// without a price are valued at zero.
+    pub fn new(pools: Vec<Cfmm>, prices: &HashMap<Address, f64>) -> Self {
+        let mut tokens = Vec::new();
+        for pool in &pools {
+            for token in pool.tokens() {
+                if !tokens.contains(&token) {
+                    tokens.push(token);
+                }
+            }
+        }
+        let prices = tokens
+            .iter()
+            .map(|token| *prices.get(token).unwrap_or(&0.0))
+            .collect();
+
+        Router {
+            tokens,
+            pools,
+            prices,
+            inventory: None,
+        }
+    }
+
+    /// Limits the net trade to what the wallet holds: each token's net may
+    /// go down by at most its balance, and tokens missing from `holdings`
+    /// must end at least even. This is how the route executes without a
+    /// flash loan.
+    pub fn with_inventory(mut self, holdings: &HashMap<Address, f64>) -> Self {
+        self.inventory = Some(
+            self.tokens
+                .iter()
+                .map(|token| holdings.get(token).cloned().unwrap_or(0.0).max(0.0))
+                .collect(),
+        );
+        self
+    }
+
+    /// Base units one trade variable stands for: the geometric mean of the
+    /// pool reserves, which keeps trades and reserves near one in the solver
+    /// whatever the tokens' decimals. Backends stop on residuals relative to
+    /// the size of the solution, so raw 18-decimal amounts would leave the
+    /// invariants loose by whole units.
+    pub fn scale(&self) -> f64 {
+        let logs: Vec<f64> = self
+            .pools
+            .iter()
+            .flat_map(|pool| {
+                let (reserve0, reserve1) = pool.reserves();
+                [reserve0, reserve1]
+            })
+            .filter(|reserve| !reserve.is_zero())
+            .map(|reserve| (reserve.as_u128() as f64).ln())
+            .collect();
+        if logs.is_empty() {
+            return 1.0;
+        }
+        (logs.iter().sum::<f64>() / logs.len() as f64).exp()
+    }
+
+    pub fn token_index(&self, token: Address) -> usize {
+        self.tokens.iter().position(|&t| t == token).unwrap()
+    }
+
+    /// First variable of each pool, plus the number of trade variables.
+    pub fn offsets(&self) -> (Vec<usize>, usize) {
+        let mut offsets = Vec::with_capacity(self.pools.len());
+        let mut n = 0;
+        for pool in &self.pools {
+            offsets.push(n);
+            n += 2 * pool.tokens().len();
+        }
+        (offsets, n)
+    }
+
+    /// Routing problem with every pool invariant relaxed by `tolerance`
+    /// (`SolverConfig::tolerance`, zero for the exact trading sets).
+    pub fn build(&self, tolerance: f64) -> ConicProblem {
+        self.build_with_layout(tolerance).0
+    }
+
+    /// `build`, plus where each pool's rows and the net trade ended up.
+    pub fn build_with_layout(&self, tolerance: f64) -> (ConicProblem, Layout) {
+        let mut prob = ConicProblem::new(self.n_vars());
+        let layout = self.add_routing(&mut prob, &self.pools, 0, tolerance, "");
+
+        // Objective: maximise prices . net
+        for (t, price) in self.prices.iter().enumerate() {
+            prob.c[layout.net + t] = -price;
+        }
+
+        (prob, layout)
+    }
+
+    /// Variables of one copy of the routing problem: trades, the auxiliary
+    /// variables of the cones, then the net trade.
+    pub fn n_vars(&self) -> usize {
+        let (_, n_trade) = self.offsets();
+        // one log variable per token of every geometric-mean pool
+        let n_aux: usize = self
+            .pools
+            .iter()
+            .map(|pool| match pool {
+                Cfmm::Balancer(pool) => pool.tokens.len(),
+                _ => 0,
+            })
+            .sum();
+        n_trade + n_aux + self.tokens.len()
+    }
+
+    /// Adds the rows of the routing problem over `pools` (`self.pools` or a
+    /// copy of them with other reserves), its variables starting at column
+    /// `first`, labels starting with `prefix`. No objective is set.
+    pub(crate) fn add_routing(
+        &self,
+        prob: &mut ConicProblem,
+        pools: &[Cfmm],
+        first: usize,
+        tolerance: f64,
+        prefix: &str,
+    ) -> Layout {
+        let (offsets, n_trade) = self.offsets();
+        let offsets: Vec<usize> = offsets.iter().map(|offset| first + offset).collect();
+        let net = first + self.n_vars() - self.tokens.len();
+        let scale = self.scale();
+
+        let mut aux = first + n_trade;
+        let mut pool_rows = Vec::with_capacity(pools.len());
+        for (i, pool) in pools.iter().enumerate() {
+            let base = offsets[i];
+            let first_row = prob.n_rows();
+            let first_block = prob.cones.len();
+            match pool {
+                Cfmm::UniV2(pool) => add_constant_product(prob, base, pool, scale, tolerance),
+                Cfmm::ConstantSum(pool) => {
+                    add_linear(prob, base, pool.trading_set(tolerance), scale)
+                }
+                // quotes are firm, there is no invariant to relax
+                Cfmm::OrderBook(book) => add_linear(prob, base, book.trading_set(), scale),
+                Cfmm::Balancer(pool) => {
+                    add_geometric_mean(prob, base, aux, pool, scale, tolerance);
+                    aux += pool.tokens.len();
+                }
+            }
+            pool_rows.push(first_row..prob.n_rows());
+            prob.label_from(
+                first_block,
+                &format!("{}pool {:?} trading set", prefix, pool.address()),
+            );
+        }
+
+        // net_t = sum over pools of lambda_t - delta_t
+        let first_row = prob.n_rows();
+        for (t, token) in self.tokens.iter().enumerate() {
+            let mut a = vec![(net + t, 1.0)];
+            for (i, pool) in pools.iter().enumerate() {
+                if let Some(k) = pool.tokens().iter().position(|pt| pt == token) {
+                    a.push((offsets[i] + 2 * k, -1.0));
+                    a.push((offsets[i] + 2 * k + 1, 1.0));
+                }
+            }
+            prob.add_eq(a, 0.0);
+            prob.label_from(
+                prob.cones.len() - 1,
+                &format!("{}net trade of token {:?}", prefix, token),
+            );
+        }
+        let net_rows = first_row..prob.n_rows();
+
+        // -net_t <= balance_t
+        if let Some(inventory) = &self.inventory {
+            for (t, token) in self.tokens.iter().enumerate() {
+                prob.add_leq(vec![(net + t, -1.0)], inventory[t] / scale);
+                prob.label_from(
+                    prob.cones.len() - 1,
+                    &format!("{}inventory of token {:?}", prefix, token),
+                );
+            }
+        }
+
+        // lambda, delta >= 0
+        for (i, pool) in pools.iter().enumerate() {
+            for (k, token) in pool.tokens().iter().enumerate() {
+                for (j, name) in ["received", "tendered"].iter().enumerate() {
+                    prob.add_leq(vec![(offsets[i] + 2 * k + j, -1.0)], 0.0);
+                    prob.label_from(
+                        prob.cones.len() - 1,
+                        &format!(
+                            "{}pool {:?} {} {:?} >= 0",
+                            prefix,
+                            pool.address(),
+                            name,
+                            token
+                        ),
+                    );
+                }
+            }
+        }
+
+        Layout {
+            offsets,
+            net,
+            pool_rows,
+            net_rows,
+        }
+    }
+
+    /// Routing problem for buying exactly `amount_out` of `token_out` with as
+    /// little `token_in` as possible: the net of `token_out` is at least
+    /// `amount_out`, every other token but `token_in` comes back at least
+    /// even, and the net of `token_in` (minus what is spent) is maximised.
+    /// Inventory, if set, still caps the spend.
+    pub fn build_exact_output(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        amount_out: f64,
+        tolerance: f64,
+    ) -> (ConicProblem, Layout) {
+        let (mut prob, layout) = self.valuing_only(token_in).build_with_layout(tolerance);
+        for (t, token) in self.tokens.iter().enumerate() {
+            if *token == token_in {
+                continue;
+            }
+            // -net_t <= -amount_out, or <= 0 for the tokens passed through
+            let (rhs, label) = if *token == token_out {
+                (
+                    -amount_out / self.scale(),
+                    format!("buy {} of token {:?}", amount_out, token),
+                )
+            } else {
+                (0.0, format!("token {:?} comes back even", token))
+            };
+            prob.add_leq(vec![(layout.net + t, -1.0)], rhs);
+            prob.label_from(prob.cones.len() - 1, &label);
+        }
+        (prob, layout)
+    }
+
+    /// Solves `build_exact_output`. The solution is valued in `token_in`
+    /// only, so its `value` is minus the amount spent.
+    pub fn solve_exact_output(
+        &self,
+        token_in: Address,
+        token_out: Address,
+        amount_out: f64,
+        config: &SolverConfig,
+    ) -> Result<RoutingSolution, SolverError> {
+        let (prob, layout) =
+            self.build_exact_output(token_in, token_out, amount_out, config.tolerance);
+        let solution = solve(&prob, config)?;
+        let routing = self
+            .valuing_only(token_in)
+            .unpack(&prob, &layout, &solution);
+
+        if !routing.verification.accepts(config.verify_slack()) {
+            return Err(SolverError::Unverified(routing.verification));
+        }
+        Ok(routing)
+    }
+
+    // The same router with only `token` valued, at one.
+    fn valuing_only(&self, token: Address) -> Router {
+        Router {
+            tokens: self.tokens.clone(),
+            pools: self.pools.clone(),
+            prices: self
+                .tokens
+                .iter()
+                .map(|t| if *t == token { 1.0 } else { 0.0 })
+                .collect(),
+            inventory: self.inventory.clone(),
+        }
+    }
+
+    /// Minimal set of named constraints (pool trading sets, net-trade rows,
+    /// inventory, non-negativity) that cannot hold together, or `None` if the
+    /// routing problem is feasible.
+    pub fn explain_infeasibility(
+        &self,
+        config: &SolverConfig,
+    ) -> Result<Option<Vec<String>>, SolverError> {
+        explain_infeasibility(&self.build(config.tolerance), config)
+    }
+
+    /// Solves the routing problem and replays the result against the exact
+    /// pool math; a solution that leaves any trading set by more than
+    /// `config.verify_slack()` is refused.
+    pub fn solve(&self, config: &SolverConfig) -> Result<RoutingSolution, SolverError> {
+        let (prob, layout) = self.build_with_layout(config.tolerance);
+        let solution = solve(&prob, config)?;
+        let routing = self.unpack(&prob, &layout, &solution);
+
+        if !routing.verification.accepts(config.verify_slack()) {
+            return Err(SolverError::Unverified(routing.verification));
+        }
+        Ok(routing)
+    }
+
+    /// `solve` through a session kept from block to block: the previous
+    /// block's solution is the starting point, and when the pools are the
+    /// same ones with new reserves the problem keeps its structure, so the
+    /// backend can reuse its set-up (see `Session`).
+    pub fn solve_in(&self, session: &mut Session) -> Result<RoutingSolution, SolverError> {
+        let (prob, layout) = self.build_with_layout(session.config.tolerance);
+        let solution = session.solve(&prob)?;
+        let routing = self.unpack(&prob, &layout, &solution);
+
+        if !routing.verification.accepts(session.config.verify_slack()) {
+            return Err(SolverError::Unverified(routing.verification));
+        }
+        Ok(routing)
+    }
+
+    /// Per-pool trades and dual prices from a solution of `prob`, verified
+    /// against the pools.
+    pub fn unpack(
+        &self,
+        prob: &ConicProblem,
+        layout: &Layout,
+        solution: &Solution,
+    ) -> RoutingSolution {
+        let (x, y) = (&solution.x, &solution.y);
+        let (trades, net) = self.trades_at(layout, x);
+
+        // what one more unit of a token leaving a pool is worth to its
+        // trading set: y over the pool's rows, weighted by lambda's column
+        let pool_prices: Vec<Vec<f64>> = trades
+            .iter()
+            .enumerate()
+            .map(|(i, trade)| {
+                (0..trade.tokens.len())
+                    .map(|k| {
+                        layout.pool_rows[i]
+                            .clone()
+                            .map(|r| y[r] * prob.coef(r, layout.offsets[i] + 2 * k))
+                            .sum::<f64>()
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let token_prices = layout.net_rows.clone().map(|r| y[r]).collect();
+
+        let post_trade_rates = trades
+            .iter()
+            .zip(&self.pools)
+            .map(|(trade, pool)| {
+                let after = pool.after_trade(&trade.received, &trade.tendered);
+                let first = trade.tokens[0];
+                trade
+                    .tokens
+                    .iter()
+                    .map(|&token| {
+                        if token == first {
+                            1.0
+                        } else {
+                            after.spot_price_to(token, first)
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let value = net.iter().zip(&self.prices).map(|(n, p)| n * p).sum();
+        let predicted = -prob.objective(x) * self.scale();
+        let verification = verify(&self.pools, &trades, &self.price_map(), predicted);
+
+        RoutingSolution {
+            trades,
+            net,
+            value,
+            token_prices,
+            pool_prices,
+            post_trade_rates,
+            verification,
+            raw: solution.clone(),
+        }
+    }
+
+    /// Per-pool trades at `x`, and the net amount per router token they add
+    /// up to.
+    pub fn trades_at(&self, layout: &Layout, x: &[f64]) -> (Vec<PoolTrade>, Vec<f64>) {
+        let scale = self.scale();
+        let mut net = vec![0.0; self.tokens.len()];
+        let mut trades = Vec::with_capacity(self.pools.len());
+        for (i, pool) in self.pools.iter().enumerate() {
+            let base = layout.offsets[i];
+            let tokens = pool.tokens();
+            let mut received = Vec::with_capacity(tokens.len());
+            let mut tendered = Vec::with_capacity(tokens.len());
+            for (k, token) in tokens.iter().enumerate() {
+                let lambda = x[base + 2 * k].max(0.0) * scale;
+                let delta = x[base + 2 * k + 1].max(0.0) * scale;
+                net[self.token_index(*token)] += lambda - delta;
+                received.push(lambda);
+                tendered.push(delta);
+            }
+            trades.push(PoolTrade {
+                pool: pool.address(),
+                tokens,
+                received,
+                tendered,
+            });
+        }
+        (trades, net)
+    }
+
+    /// `prices` by token.
+    pub fn price_map(&self) -> HashMap<Address, f64> {
+        self.tokens
+            .iter()
+            .cloned()
+            .zip(self.prices.iter().cloned())
+            .collect()
+    }
+}
+
+/// Rows `a . x <= b` over the pool's `[lambda0, delta0, lambda1, delta1]`,
+/// `b` in base units.
+fn add_linear(prob: &mut ConicProblem, base: usize, rows: Vec<([f64; 4], f64)>, scale: f64) {
+    for (coefs, rhs) in rows {
+        let a = coefs
+            .iter()
+            .enumerate()
+            .map(|(k, coef)| (base + k, *coef))
+            .collect();
+        prob.add_leq(a, rhs / scale);
+    }
+}
+
+/// (R0 + fee * delta0 - lambda0)(R1 + fee * delta1 - lambda1) >= k with
+/// k = (1 - tolerance) R0 R1, as the hyperbolic cone ||(2 sqrt(k), u - v)|| <= u + v.
+fn add_constant_product(
+    prob: &mut ConicProblem,
+    base: usize,
+    pool: &UniV2Pool,
+    scale: f64,
+    tolerance: f64,
+) {
+    let reserve0 = pool.reserve0.as_u128() as f64 / scale;
+    let reserve1 = pool.reserve1.as_u128() as f64 / scale;
+    let fee = pool.fee();
+
+    // rows of u = R0 + fee * delta0 - lambda0 and v = R1 + fee * delta1 - lambda1
+    // are (1, -fee, 0, 0) and (0, 0, 1, -fee) over the pool's variables
+    let sum: SparseRow = vec![
+        (base, 1.0),
+        (base + 1, -fee),
+        (base + 2, 1.0),
+        (base + 3, -fee),
+    ];
+    let diff: SparseRow = vec![
+        (base, 1.0),
+        (base + 1, -fee),
+        (base + 2, -1.0),
+        (base + 3, fee),
+    ];
+
+    prob.add_block(
+        Cone::SecondOrder(3),
+        vec![sum, Vec::new(), diff],
+        vec![
+            reserve0 + reserve1,
+            2.0 * ((1.0 - tolerance) * reserve0 * reserve1).sqrt(),
+            reserve0 - reserve1,
+        ],
+    );
+}
+
+/// sum_j w_j log(R_j + fee * delta_j - lambda_j) >= sum_j w_j log(R_j) + log(1 - tolerance),
+/// with one exponential cone (t_j, 1, R_j + fee * delta_j - lambda_j) per
+/// token bounding the auxiliary t_j by the log of the new reserve.
+fn add_geometric_mean(
+    prob: &mut ConicProblem,
+    base: usize,
+    aux: usize,
+    pool: &BalancerPool,
+    scale: f64,
+    tolerance: f64,
+) {
+    let fee = pool.fee();
+    let mut invariant: SparseRow = Vec::with_capacity(pool.reserves.len());
+    let mut log_invariant = (1.0 - tolerance).ln();
+
+    for (j, reserve) in pool.reserves.iter().enumerate() {
+        let reserve = reserve.as_u128() as f64 / scale;
+        let t = aux + j;
+
+        let row_t = vec![(t, -1.0)];
+        let row_reserve = vec![(base + 2 * j, 1.0), (base + 2 * j + 1, -fee)];
+
+        prob.add_block(
+            Cone::Exp,
+            vec![row_t, Vec::new(), row_reserve],
+            vec![0.0, 1.0, reserve],
+        );
+
+        invariant.push((t, -pool.weights[j]));
+        log_invariant += pool.weights[j] * reserve.ln();
+    }
+
+    prob.add_leq(invariant, -log_invariant);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::solver::Backend;
+
+    #[test]
+    fn post_trade_rates_close_the_arbitrage() {
+        let tokens: Vec<Address> = (0..2u8).map(|i| Address::from([i; 20])).collect();
+        // the pool sells token 1 at par, the market values it at two
+        let pools = vec![UniV2Pool::fixture(
+            0x10,
+            tokens[0],
+            tokens[1],
+            (1_000_000_000, 1_000_000_000),
+        )];
+        let prices = HashMap::from([(tokens[0], 1.0), (tokens[1], 2.0)]);
+        let router = Router::new(pools, &prices);
+        let config = SolverConfig::from_env_or(Backend::Clarabel).unwrap();
+
+        let solution = router.solve(&config).unwrap();
+        let before = router.pools[0].spot_price_to(tokens[1], tokens[0]);
+        let after = solution.post_trade_rates[0][1];
+
+        // no inventory binds, so the duals only echo the inputs
+        for (dual, price) in solution.token_prices.iter().zip(&router.prices) {
+            assert!((dual - price).abs() < 1e-6);
+        }
+        // token 1 got dearer in the pool until trading it either way at the
+        // market price no longer pays: 2 fee^2 <= rate <= 2
+        // at par, the rate before the trade is the fee alone
+        let fee = before;
+        assert!(after > before);
+        assert!(after >= 2.0 * fee * fee - 1e-6 && after <= 2.0);
+        assert_eq!(solution.post_trade_rates[0][0], 1.0);
+    }
+
+    #[test]
+    fn binding_inventory_is_spent_exactly() {
+        let tokens: Vec<Address> = (0..2u8).map(|i| Address::from([i; 20])).collect();
+        let pools = vec![UniV2Pool::fixture(
+            0x10,
+            tokens[0],
+            tokens[1],
+            (1_000_000_000, 1_000_000_000),
+        )];
+        let prices = HashMap::from([(tokens[0], 1.0), (tokens[1], 2.0)]);
+        // far less token 0 than closing the arbitrage takes
+        let balance = 1_000_000.0;
+        let router =
+            Router::new(pools, &prices).with_inventory(&HashMap::from([(tokens[0], balance)]));
+        let config = SolverConfig::from_env_or(Backend::Clarabel).unwrap();
+
+        let solution = router.solve(&config).unwrap();
+        // the backend is accurate to eps in scaled units
+        let accuracy = config.eps * router.scale();
+        assert!((solution.net[0] + balance).abs() <= accuracy);
+        assert!(solution.net[1] > 0.0);
+
+        // one more unit of token 0 is worth what the pool pays for it
+        let rate = 2.0 * router.pools[0].spot_price_to(tokens[0], tokens[1]);
+        assert!(solution.token_prices[0] > router.prices[0]);
+        assert!((solution.token_prices[0] - rate).abs() <= 1e-2 * rate);
+    }
+}
@@ -0,0 +1,332 @@
+//! Minimal bindings to the SCS 3.2 C library (`libscsdir`, built with 32-bit
+//! `scs_int`), enough to hand it a `ConicProblem`.
+
+use std::os::raw::{c_char, c_int};
+
+use crate::solver::{
+    csc_columns, Backend, Cone, ConicProblem, Solution, SolveInfo, SolveOutcome, SolverConfig,
+    SolverError, SparseRow,
+};
+
+type ScsInt = c_int;
+type ScsFloat = f64;
+
+#[repr(C)]
+struct ScsMatrix {
+    x: *mut ScsFloat,
+    i: *mut ScsInt,
+    p: *mut ScsInt,
+    m: ScsInt,
+    n: ScsInt,
+}
+
+#[repr(C)]
+struct ScsData {
+    m: ScsInt,
+    n: ScsInt,
+    a: *mut ScsMatrix,
+    p: *mut ScsMatrix,
+    b: *mut ScsFloat,
+    c: *mut ScsFloat,
+}
+
+#[repr(C)]
+struct ScsCone {
+    z: ScsInt,
+    l: ScsInt,
+    bu: *mut ScsFloat,
+    bl: *mut ScsFloat,
+    bsize: ScsInt,
+    q: *mut ScsInt,
+    qsize: ScsInt,
+    s: *mut ScsInt,
+    ssize: ScsInt,
+    ep: ScsInt,
+    ed: ScsInt,
+    p: *mut ScsFloat,
+    psize: ScsInt,
+}
+
+#[repr(C)]
+struct ScsSettings {
+    normalize: ScsInt,
+    scale: ScsFloat,
+    adaptive_scale: ScsInt,
+    rho_x: ScsFloat,
+    max_iters: ScsInt,
+    eps_abs: ScsFloat,
+    eps_rel: ScsFloat,
+    eps_infeas: ScsFloat,
+    alpha: ScsFloat,
+    time_limit_secs: ScsFloat,
+    verbose: ScsInt,
+    warm_start: ScsInt,
+    acceleration_lookback: ScsInt,
+    acceleration_interval: ScsInt,
+    write_data_filename: *const c_char,
+    log_csv_filename: *const c_char,
+}
+
+#[repr(C)]
+struct ScsSolution {
+    x: *mut ScsFloat,
+    y: *mut ScsFloat,
+    s: *mut ScsFloat,
+}
+
+#[repr(C)]
+struct ScsInfo {
+    iter: ScsInt,
+    status: [c_char; 128],
+    lin_sys_solver: [c_char; 128],
+    status_val: ScsInt,
+    scale_updates: ScsInt,
+    pobj: ScsFloat,
+    dobj: ScsFloat,
+    res_pri: ScsFloat,
+    res_dual: ScsFloat,
+    gap: ScsFloat,
+    res_infeas: ScsFloat,
+    res_unbdd_a: ScsFloat,
+    res_unbdd_p: ScsFloat,
+    setup_time: ScsFloat,
+    solve_time: ScsFloat,
+    scale: ScsFloat,
+    comp_slack: ScsFloat,
+    rejected_accel_steps: ScsInt,
+    accepted_accel_steps: ScsInt,
+    lin_sys_time: ScsFloat,
+    cone_time: ScsFloat,
+    accel_time: ScsFloat,
+}
+
+// Opaque solver state: the factorised, scaled problem.
+enum ScsWork {}
+
+extern "C" {
+    fn scs_set_default_settings(stgs: *mut ScsSettings);
+    fn scs_init(d: *const ScsData, k: *const ScsCone, stgs: *const ScsSettings) -> *mut ScsWork;
+    fn scs_update(w: *mut ScsWork, b: *mut ScsFloat, c: *mut ScsFloat) -> ScsInt;
+    fn scs_solve(
+        w: *mut ScsWork,
+        sol: *mut ScsSolution,
+        info: *mut ScsInfo,
+        warm_start: ScsInt,
+    ) -> ScsInt;
+    fn scs_finish(w: *mut ScsWork);
+}
+
+// SCS wants its cones grouped as zero, linear, second-order, exp, power.
+fn cone_rank(cone: &Cone) -> usize {
+    match cone {
+        Cone::Zero(_) => 0,
+        Cone::NonNeg(_) => 1,
+        Cone::SecondOrder(_) => 2,
+        Cone::Exp => 3,
+        Cone::Power(_) => 4,
+    }
+}
+
+/// SCS set up for one problem structure. Set-up factorises `A`; later
+/// solves of a problem that differs only in `b` and `c` go through
+/// `scs_update` and keep the factorisation. Set-up fails when SCS rejects
+/// the data, e.g. on invalid cone sizes.
+pub(crate) struct Workspace {
+    work: *mut ScsWork,
+    // `order[k]` is our row index of SCS row k
+    order: Vec<usize>,
+    // buffers SCS was handed, kept for as long as `work` in case it points
+    // into them
+    colptr: Vec<ScsInt>,
+    rowval: Vec<ScsInt>,
+    nzval: Vec<f64>,
+    b: Vec<f64>,
+    c: Vec<f64>,
+    q: Vec<ScsInt>,
+    p: Vec<f64>,
+    max_iter: usize,
+}
+
+impl Workspace {
+    pub(crate) fn new(problem: &ConicProblem, config: &SolverConfig) -> Result<Self, SolverError> {
+        let n_vars = problem.n_vars;
+        let m = problem.n_rows();
+
+        // Row blocks reordered the way SCS wants them
+        let mut blocks: Vec<(usize, &Cone)> = Vec::new();
+        let mut start = 0;
+        for cone in &problem.cones {
+            blocks.push((start, cone));
+            start += cone.dim();
+        }
+        blocks.sort_by_key(|(_, cone)| cone_rank(cone));
+
+        let mut order = Vec::with_capacity(m);
+        let mut z = 0;
+        let mut l = 0;
+        let mut q = Vec::new();
+        let mut ep = 0;
+        let mut p = Vec::new();
+        for (start, cone) in &blocks {
+            order.extend(*start..*start + cone.dim());
+            match **cone {
+                Cone::Zero(n) => z += n as ScsInt,
+                Cone::NonNeg(n) => l += n as ScsInt,
+                Cone::SecondOrder(n) => q.push(n as ScsInt),
+                Cone::Exp => ep += 1,
+                Cone::Power(alpha) => p.push(alpha),
+            }
+        }
+
+        let rows: Vec<SparseRow> = order.iter().map(|&r| problem.a[r].clone()).collect();
+        let (colptr, rowval, nzval) = csc_columns(&rows, n_vars);
+
+        let mut workspace = Workspace {
+            work: std::ptr::null_mut(),
+            b: order.iter().map(|&r| problem.b[r]).collect(),
+            c: problem.c.clone(),
+            order,
+            colptr: colptr.iter().map(|&v| v as ScsInt).collect(),
+            rowval: rowval.iter().map(|&v| v as ScsInt).collect(),
+            nzval,
+            q,
+            p,
+            max_iter: config.max_iter,
+        };
+
+        let mut mat_a = ScsMatrix {
+            x: workspace.nzval.as_mut_ptr(),
+            i: workspace.rowval.as_mut_ptr(),
+            p: workspace.colptr.as_mut_ptr(),
+            m: m as ScsInt,
+            n: n_vars as ScsInt,
+        };
+        let data = ScsData {
+            m: m as ScsInt,
+            n: n_vars as ScsInt,
+            a: &mut mat_a,
+            p: std::ptr::null_mut(),
+            b: workspace.b.as_mut_ptr(),
+            c: workspace.c.as_mut_ptr(),
+        };
+        let cone = ScsCone {
+            z,
+            l,
+            bu: std::ptr::null_mut(),
+            bl: std::ptr::null_mut(),
+            bsize: 0,
+            q: workspace.q.as_mut_ptr(),
+            qsize: workspace.q.len() as ScsInt,
+            s: std::ptr::null_mut(),
+            ssize: 0,
+            ep,
+            ed: 0,
+            p: workspace.p.as_mut_ptr(),
+            psize: workspace.p.len() as ScsInt,
+        };
+
+        workspace.work = unsafe {
+            let mut settings: ScsSettings = std::mem::zeroed();
+            scs_set_default_settings(&mut settings);
+            settings.max_iters = config.max_iter as ScsInt;
+            settings.eps_abs = config.eps;
+            settings.eps_rel = config.eps;
+            settings.verbose = config.verbose as ScsInt;
+
+            scs_init(&data, &cone, &settings)
+        };
+        if workspace.work.is_null() {
+            return Err(SolverError::SetupFailed(Backend::Scs));
+        }
+        Ok(workspace)
+    }
+
+    /// New `b` and `c` for a problem with the structure this workspace was
+    /// set up with.
+    pub(crate) fn update(&mut self, problem: &ConicProblem) {
+        for (k, &r) in self.order.iter().enumerate() {
+            self.b[k] = problem.b[r];
+        }
+        self.c.copy_from_slice(&problem.c);
+        unsafe {
+            scs_update(self.work, self.b.as_mut_ptr(), self.c.as_mut_ptr());
+        }
+    }
+
+    /// Solves, starting from `start` when given (its slack is recomputed
+    /// from the problem).
+    pub(crate) fn solve(
+        &mut self,
+        problem: &ConicProblem,
+        start: Option<&Solution>,
+    ) -> SolveOutcome {
+        let n_vars = problem.n_vars;
+        let m = problem.n_rows();
+
+        let mut x = vec![0.0; n_vars];
+        let mut y = vec![0.0; m];
+        let mut s = vec![0.0; m];
+        if let Some(start) = start {
+            x.copy_from_slice(&start.x);
+            for (k, &r) in self.order.iter().enumerate() {
+                y[k] = start.y[r];
+                s[k] = problem.b[r]
+                    - problem.a[r]
+                        .iter()
+                        .map(|(j, a)| a * start.x[*j])
+                        .sum::<f64>();
+            }
+        }
+        let mut sol = ScsSolution {
+            x: x.as_mut_ptr(),
+            y: y.as_mut_ptr(),
+            s: s.as_mut_ptr(),
+        };
+
+        let mut info: ScsInfo = unsafe { std::mem::zeroed() };
+        let status_val =
+            unsafe { scs_solve(self.work, &mut sol, &mut info, start.is_some() as ScsInt) };
+
+        let mut duals = vec![0.0; m];
+        for (k, &r) in self.order.iter().enumerate() {
+            duals[r] = y[k];
+        }
+        let solution = Solution { x, y: duals };
+        let iterations = info.iter as usize;
+        let info = SolveInfo::measure(problem, &solution, Some(iterations));
+
+        match status_val {
+            1 => SolveOutcome::Optimal { solution, info },
+            // solved inaccurate: either out of iterations or stalled close to optimal
+            2 if iterations >= self.max_iter => SolveOutcome::IterationLimit {
+                solution: Some(solution),
+                info,
+            },
+            2 => SolveOutcome::Optimal { solution, info },
+            -2 | -7 => SolveOutcome::Infeasible {
+                certificate: Some(solution.y),
+                info,
+            },
+            -1 | -6 => SolveOutcome::Unbounded {
+                certificate: Some(solution.x),
+                info,
+            },
+            status => SolveOutcome::NumericalError {
+                message: format!("status {}", status),
+                info,
+            },
+        }
+    }
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        if !self.work.is_null() {
+            unsafe { scs_finish(self.work) };
+        }
+    }
+}
+
+pub fn solve(problem: &ConicProblem, config: &SolverConfig) -> Result<SolveOutcome, SolverError> {
+    Ok(Workspace::new(problem, config)?.solve(problem, None))
+}
@@ -0,0 +1,800 @@
+use std::fmt;
+use std::str::FromStr;
+
+use totsu::prelude::SolverError as TotsuError;
+use totsu::prelude::*;
+use totsu::{MatBuild, ProbSOCP};
+
+use crate::verify::Verification;
+
+type La = FloatGeneric<f64>;
+
+/// Cone a block of constraint rows `s = b - A x` has to lie in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Cone {
+    // s = 0
+    Zero(usize),
+    // s >= 0
+    NonNeg(usize),
+    // s[0] >= ||s[1..]||
+    SecondOrder(usize),
+    // (x, y, z) with y * exp(x / y) <= z, y > 0
+    Exp,
+    // (x, y, z) with x^a * y^(1 - a) >= |z|, x, y >= 0
+    Power(f64),
+}
+
+impl Cone {
+    pub fn dim(&self) -> usize {
+        match *self {
+            Cone::Zero(n) | Cone::NonNeg(n) | Cone::SecondOrder(n) => n,
+            Cone::Exp | Cone::Power(_) => 3,
+        }
+    }
+}
+
+/// One row of `A` as `(column, value)` pairs, structural non-zeros only.
+pub type SparseRow = Vec<(usize, f64)>;
+
+/// Sparse copy of a dense row, dropping exact zeros.
+pub fn sparse_row(row: &[f64]) -> SparseRow {
+    row.iter()
+        .enumerate()
+        .filter(|(_, v)| **v != 0.0)
+        .map(|(j, v)| (j, *v))
+        .collect()
+}
+
+/// Backend-neutral conic program
+///
+///   minimize c . x  subject to  b - A x in K
+///
+/// with K the product of `cones` in row order. This is the standard form of
+/// Clarabel and SCS; totsu gets it through `ProbSOCP`.
+///
+/// `A` is kept row by row in sparse form, so a pool adds only the handful of
+/// entries its own variables need and the size of the problem grows with
+/// the number of non-zeros, not rows times variables.
+#[derive(Debug, Clone)]
+pub struct ConicProblem {
+    pub n_vars: usize,
+    pub c: Vec<f64>,
+    // sorted by column, no duplicate columns, no zeros
+    pub a: Vec<SparseRow>,
+    pub b: Vec<f64>,
+    pub cones: Vec<Cone>,
+    // what each cone block stands for, empty when unlabelled
+    pub labels: Vec<String>,
+}
+
+impl ConicProblem {
+    pub fn new(n_vars: usize) -> Self {
+        ConicProblem {
+            n_vars,
+            c: vec![0.0; n_vars],
+            a: Vec::new(),
+            b: Vec::new(),
+            cones: Vec::new(),
+            labels: Vec::new(),
+        }
+    }
+
+    pub fn n_rows(&self) -> usize {
+        self.b.len()
+    }
+
+    /// Appends one cone block; `rows[k] . x` and `rhs[k]` give its k-th slack.
+    /// Entries of a row that share a column are added up.
+    pub fn add_block(&mut self, cone: Cone, rows: Vec<SparseRow>, rhs: Vec<f64>) {
+        assert_eq!(rows.len(), cone.dim());
+        assert_eq!(rhs.len(), cone.dim());
+        for mut row in rows {
+            row.sort_by_key(|(j, _)| *j);
+            let mut merged: SparseRow = Vec::with_capacity(row.len());
+            for (j, v) in row {
+                assert!(j < self.n_vars, "column {} out of range", j);
+                match merged.last_mut() {
+                    Some((last, total)) if *last == j => *total += v,
+                    _ => merged.push((j, v)),
+                }
+            }
+            merged.retain(|(_, v)| *v != 0.0);
+            self.a.push(merged);
+        }
+        self.b.extend(rhs);
+        self.cones.push(cone);
+        self.labels.push(String::new());
+    }
+
+    /// Names every block from `first` on, e.g. after the rows of one pool.
+    pub fn label_from(&mut self, first: usize, label: &str) {
+        for l in &mut self.labels[first..] {
+            *l = label.to_string();
+        }
+    }
+
+    /// Entry of `A` at (`row`, `col`).
+    pub fn coef(&self, row: usize, col: usize) -> f64 {
+        let row = &self.a[row];
+        match row.binary_search_by_key(&col, |(j, _)| *j) {
+            Ok(k) => row[k].1,
+            Err(_) => 0.0,
+        }
+    }
+
+    /// Number of structural non-zeros of `A`.
+    pub fn nnz(&self) -> usize {
+        self.a.iter().map(|row| row.len()).sum()
+    }
+
+    /// a . x <= b
+    pub fn add_leq(&mut self, a: SparseRow, b: f64) {
+        self.add_block(Cone::NonNeg(1), vec![a], vec![b]);
+    }
+
+    /// a . x == b
+    pub fn add_eq(&mut self, a: SparseRow, b: f64) {
+        self.add_block(Cone::Zero(1), vec![a], vec![b]);
+    }
+
+    /// ||G x + h|| <= c . x + d, the form `ProbSOCP` takes its cones in.
+    pub fn add_soc(&mut self, g: Vec<SparseRow>, h: Vec<f64>, c: SparseRow, d: f64) {
+        let negate = |row: &SparseRow| row.iter().map(|(j, v)| (*j, -v)).collect::<SparseRow>();
+        let mut rows = vec![negate(&c)];
+        rows.extend(g.iter().map(negate));
+        let mut rhs = vec![d];
+        rhs.extend(h);
+        self.add_block(Cone::SecondOrder(rows.len()), rows, rhs);
+    }
+
+    /// Same variables, cones and `A`; the two may differ only in `b` and
+    /// `c`, which is all a change of reserves or prices touches.
+    pub fn same_structure(&self, other: &ConicProblem) -> bool {
+        self.n_vars == other.n_vars && self.cones == other.cones && self.a == other.a
+    }
+
+    pub fn objective(&self, x: &[f64]) -> f64 {
+        self.c.iter().zip(x).map(|(c, x)| c * x).sum()
+    }
+
+    /// Largest amount by which the slack `b - A x` leaves its cone.
+    pub fn primal_residual(&self, x: &[f64]) -> f64 {
+        let slack: Vec<f64> = self
+            .a
+            .iter()
+            .zip(&self.b)
+            .map(|(row, b)| b - row.iter().map(|(j, a)| a * x[*j]).sum::<f64>())
+            .collect();
+
+        let mut residual: f64 = 0.0;
+        let mut row = 0;
+        for cone in &self.cones {
+            let s = &slack[row..row + cone.dim()];
+            let violation = match *cone {
+                Cone::Zero(_) => s.iter().map(|v| v.abs()).fold(0.0_f64, f64::max),
+                Cone::NonNeg(_) => s.iter().map(|v| -v).fold(0.0_f64, f64::max),
+                Cone::SecondOrder(_) => {
+                    let norm = s[1..].iter().map(|v| v * v).sum::<f64>().sqrt();
+                    norm - s[0]
+                }
+                Cone::Exp => {
+                    if s[1] > 0.0 {
+                        s[1] * (s[0] / s[1]).exp() - s[2]
+                    } else {
+                        s[0].max(-s[1]).max(-s[2])
+                    }
+                }
+                Cone::Power(alpha) => {
+                    let mean = s[0].max(0.0).powf(alpha) * s[1].max(0.0).powf(1.0 - alpha);
+                    (s[2].abs() - mean).max(-s[0]).max(-s[1])
+                }
+            };
+            residual = residual.max(violation);
+            row += cone.dim();
+        }
+        residual
+    }
+
+    /// Largest entry of `A' y + c`, zero at a dual feasible `y`.
+    pub fn dual_residual(&self, y: &[f64]) -> f64 {
+        let mut r = self.c.clone();
+        for (row, y) in self.a.iter().zip(y) {
+            for (j, a) in row {
+                r[*j] += a * y;
+            }
+        }
+        r.iter().fold(0.0, |m, v| m.max(v.abs()))
+    }
+
+    /// `c . x + b . y`, the gap between the primal and dual objectives.
+    pub fn duality_gap(&self, x: &[f64], y: &[f64]) -> f64 {
+        self.objective(x) + self.b.iter().zip(y).map(|(b, y)| b * y).sum::<f64>()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Totsu,
+    Clarabel,
+    Scs,
+}
+
+impl FromStr for Backend {
+    type Err = SolverError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "totsu" => Ok(Backend::Totsu),
+            "clarabel" => Ok(Backend::Clarabel),
+            "scs" => Ok(Backend::Scs),
+            _ => Err(SolverError::UnknownBackend(s.to_string())),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SolverConfig {
+    pub backend: Backend,
+    pub max_iter: usize,
+    // accuracy asked of the backend (totsu eps_acc, Clarabel/SCS gap and residuals)
+    pub eps: f64,
+    /// Relative slack on every pool invariant: a trade is accepted when the
+    /// post-trade invariant is at least `(1 - tolerance)` times the current
+    /// one. Zero, the default, encodes the exact trading sets; anything larger
+    /// is a deliberate relaxation and any profit it buys is not executable.
+    pub tolerance: f64,
+    pub verbose: bool,
+}
+
+impl Default for SolverConfig {
+    fn default() -> Self {
+        SolverConfig {
+            backend: Backend::Totsu,
+            max_iter: 100000,
+            eps: 1e-8,
+            tolerance: 0.0,
+            verbose: false,
+        }
+    }
+}
+
+impl SolverConfig {
+    /// Largest relative invariant violation a verified solution may show: the
+    /// configured relaxation plus the accuracy asked of the backend.
+    pub fn verify_slack(&self) -> f64 {
+        self.tolerance + self.eps
+    }
+
+    /// Default config with the backend taken from `CFMM_SOLVER`, if set.
+    pub fn from_env() -> Result<Self, SolverError> {
+        Self::from_env_or(Backend::Totsu)
+    }
+
+    /// Same as `from_env`, falling back to `backend` when `CFMM_SOLVER` is unset.
+    pub fn from_env_or(backend: Backend) -> Result<Self, SolverError> {
+        let mut config = SolverConfig {
+            backend,
+            ..SolverConfig::default()
+        };
+        if let Ok(name) = std::env::var("CFMM_SOLVER") {
+            config.backend = name.parse()?;
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Debug)]
+pub enum SolverError {
+    UnknownBackend(String),
+    // backend not compiled in, enable its cargo feature
+    BackendUnavailable(Backend),
+    UnsupportedCone(Backend, Cone),
+    // the backend refused the problem before solving, e.g. SCS set-up
+    SetupFailed(Backend),
+    // the backend ran but did not reach an optimum
+    NotSolved(Backend, Box<SolveOutcome>),
+    // solved, but the trades do not hold up against the exact pool math
+    Unverified(Verification),
+}
+
+impl fmt::Display for SolverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolverError::UnknownBackend(name) => write!(f, "unknown solver backend {:?}", name),
+            SolverError::BackendUnavailable(backend) => {
+                write!(f, "{:?} backend is not enabled in this build", backend)
+            }
+            SolverError::UnsupportedCone(backend, cone) => {
+                write!(f, "{:?} backend does not support {:?} cones", backend, cone)
+            }
+            SolverError::SetupFailed(backend) => {
+                write!(f, "{:?} backend failed to set up the problem", backend)
+            }
+            SolverError::NotSolved(backend, outcome) => write!(f, "{:?}: {}", backend, outcome),
+            SolverError::Unverified(verification) => match verification.worst() {
+                Some(check) => write!(
+                    f,
+                    "solution rejected, pool {} violated by {:.3e}",
+                    check.pool, check.violation
+                ),
+                None => write!(f, "solution rejected"),
+            },
+        }
+    }
+}
+
+impl std::error::Error for SolverError {}
+
+/// Primal `x` and dual `y` (one entry per row of the problem).
+#[derive(Debug, Clone)]
+pub struct Solution {
+    pub x: Vec<f64>,
+    pub y: Vec<f64>,
+}
+
+/// Convergence figures of a run; residuals and gap are measured on the
+/// returned point, so they mean the same thing for every backend.
+#[derive(Debug, Clone, Default)]
+pub struct SolveInfo {
+    // None when the backend does not report it (totsu)
+    pub iterations: Option<usize>,
+    pub primal_residual: f64,
+    pub dual_residual: f64,
+    pub duality_gap: f64,
+}
+
+impl SolveInfo {
+    pub fn measure(problem: &ConicProblem, solution: &Solution, iterations: Option<usize>) -> Self {
+        SolveInfo {
+            iterations,
+            primal_residual: problem.primal_residual(&solution.x),
+            dual_residual: problem.dual_residual(&solution.y),
+            duality_gap: problem.duality_gap(&solution.x, &solution.y),
+        }
+    }
+
+    fn iterations(iterations: Option<usize>) -> Self {
+        SolveInfo {
+            iterations,
+            ..SolveInfo::default()
+        }
+    }
+}
+
+/// How a backend run ended.
+#[derive(Debug, Clone)]
+pub enum SolveOutcome {
+    Optimal {
+        solution: Solution,
+        info: SolveInfo,
+    },
+    // certificate y in the dual cone with A' y = 0 and b . y < 0, if given
+    Infeasible {
+        certificate: Option<Vec<f64>>,
+        info: SolveInfo,
+    },
+    // certificate x with c . x < 0 and -A x in the cone, if given
+    Unbounded {
+        certificate: Option<Vec<f64>>,
+        info: SolveInfo,
+    },
+    // out of iterations; the last iterate when the backend hands it back
+    IterationLimit {
+        solution: Option<Solution>,
+        info: SolveInfo,
+    },
+    NumericalError {
+        message: String,
+        info: SolveInfo,
+    },
+}
+
+impl SolveOutcome {
+    pub fn info(&self) -> &SolveInfo {
+        match self {
+            SolveOutcome::Optimal { info, .. }
+            | SolveOutcome::Infeasible { info, .. }
+            | SolveOutcome::Unbounded { info, .. }
+            | SolveOutcome::IterationLimit { info, .. }
+            | SolveOutcome::NumericalError { info, .. } => info,
+        }
+    }
+
+    pub fn is_optimal(&self) -> bool {
+        matches!(self, SolveOutcome::Optimal { .. })
+    }
+}
+
+impl fmt::Display for SolveOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolveOutcome::Optimal { .. } => write!(f, "optimal")?,
+            SolveOutcome::Infeasible { certificate, .. } => write!(
+                f,
+                "infeasible ({} certificate)",
+                if certificate.is_some() { "with" } else { "no" }
+            )?,
+            SolveOutcome::Unbounded { certificate, .. } => write!(
+                f,
+                "unbounded ({} certificate)",
+                if certificate.is_some() { "with" } else { "no" }
+            )?,
+            SolveOutcome::IterationLimit { .. } => write!(f, "iteration limit reached")?,
+            SolveOutcome::NumericalError { message, .. } => {
+                write!(f, "numerical error: {}", message)?
+            }
+        }
+
+        let info = self.info();
+        if let Some(iterations) = info.iterations {
+            write!(f, " after {} iterations", iterations)?;
+        }
+        write!(
+            f,
+            ", primal residual {:.3e}, dual residual {:.3e}, gap {:.3e}",
+            info.primal_residual, info.dual_residual, info.duality_gap
+        )
+    }
+}
+
+/// Runs the configured backend and reports how it ended. `Err` is kept for
+/// problems the backend could not even be started on.
+pub fn solve_outcome(
+    problem: &ConicProblem,
+    config: &SolverConfig,
+) -> Result<SolveOutcome, SolverError> {
+    match config.backend {
+        Backend::Totsu => solve_totsu(problem, config),
+        Backend::Clarabel => solve_clarabel(problem, config),
+        Backend::Scs => solve_scs(problem, config),
+    }
+}
+
+/// Optimal solution, or `SolverError::NotSolved` carrying the outcome.
+pub fn solve(problem: &ConicProblem, config: &SolverConfig) -> Result<Solution, SolverError> {
+    match solve_outcome(problem, config)? {
+        SolveOutcome::Optimal { solution, .. } => Ok(solution),
+        outcome => Err(SolverError::NotSolved(config.backend, Box::new(outcome))),
+    }
+}
+
+/// Solver state carried across repeated solves, e.g. the routing problem of
+/// consecutive blocks. With SCS each solve starts from the last optimal
+/// point (or the one given to `warm_start`) when the dimensions still match,
+/// and when only `b` and `c` changed since the previous solve the
+/// factorisation set up then is kept. SCS is the only backend a session
+/// warm-starts, and needs the `scs` feature; on totsu or Clarabel every
+/// problem is solved cold, and `Session::new` logs a warning saying so.
+pub struct Session {
+    pub config: SolverConfig,
+    // last problem solved, to tell whether its structure changed
+    previous: Option<ConicProblem>,
+    // starting point of the next solve
+    start: Option<Solution>,
+    #[cfg(feature = "scs")]
+    scs: Option<crate::scs::Workspace>,
+}
+
+impl Session {
+    pub fn new(config: SolverConfig) -> Self {
+        if !Self::warm_starts_on(config.backend) {
+            log::warn!(
+                "{:?} session solves every problem cold; warm start needs the scs backend",
+                config.backend
+            );
+        }
+        Session {
+            config,
+            previous: None,
+            start: None,
+            #[cfg(feature = "scs")]
+            scs: None,
+        }
+    }
+
+    /// True when solves in this session start from the previous solution.
+    pub fn warm_starts(&self) -> bool {
+        Self::warm_starts_on(self.config.backend)
+    }
+
+    fn warm_starts_on(backend: Backend) -> bool {
+        cfg!(feature = "scs") && backend == Backend::Scs
+    }
+
+    /// Start the next solve from `solution`, e.g. the previous block's; kept
+    /// but unused unless the session `warm_starts`.
+    pub fn warm_start(&mut self, solution: Solution) {
+        self.start = Some(solution);
+    }
+
+    /// True when the last problem solved had the structure of `problem`.
+    pub fn reuses(&self, problem: &ConicProblem) -> bool {
+        self.previous
+            .as_ref()
+            .is_some_and(|previous| previous.same_structure(problem))
+    }
+
+    pub fn solve_outcome(&mut self, problem: &ConicProblem) -> Result<SolveOutcome, SolverError> {
+        let reuse = self.reuses(problem);
+
+        let outcome = match self.config.backend {
+            #[cfg(feature = "scs")]
+            Backend::Scs => {
+                if !reuse {
+                    self.scs = None;
+                }
+                let start = self
+                    .start
+                    .as_ref()
+                    .filter(|s| s.x.len() == problem.n_vars && s.y.len() == problem.n_rows());
+                let workspace = match self.scs.take() {
+                    Some(workspace) => workspace,
+                    None => crate::scs::Workspace::new(problem, &self.config)?,
+                };
+                let workspace = self.scs.insert(workspace);
+                if reuse {
+                    workspace.update(problem);
+                }
+                workspace.solve(problem, start)
+            }
+            _ => solve_outcome(problem, &self.config)?,
+        };
+
+        if !reuse {
+            self.previous = Some(problem.clone());
+        } else if let Some(previous) = &mut self.previous {
+            previous.b.clone_from(&problem.b);
+            previous.c.clone_from(&problem.c);
+        }
+        if let SolveOutcome::Optimal { solution, .. } = &outcome {
+            self.start = Some(solution.clone());
+        }
+        Ok(outcome)
+    }
+
+    /// Optimal solution, or `SolverError::NotSolved` carrying the outcome.
+    pub fn solve(&mut self, problem: &ConicProblem) -> Result<Solution, SolverError> {
+        match self.solve_outcome(problem)? {
+            SolveOutcome::Optimal { solution, .. } => Ok(solution),
+            outcome => Err(SolverError::NotSolved(
+                self.config.backend,
+                Box::new(outcome),
+            )),
+        }
+    }
+}
+
+// `ProbSOCP` only takes dense `MatBuild`s, one per cone, so only the
+// non-zeros are written but the matrices are still allocated in full. Large
+// problems belong on Clarabel or SCS, which take `A` column-compressed.
+fn solve_totsu(problem: &ConicProblem, config: &SolverConfig) -> Result<SolveOutcome, SolverError> {
+    let n_vars = problem.n_vars;
+
+    let mut vec_f = MatBuild::new(MatType::General(n_vars, 1));
+    for (i, c) in problem.c.iter().enumerate() {
+        vec_f[(i, 0)] = *c;
+    }
+
+    // Zero rows become A x = b, everything else one ProbSOCP cone each:
+    // a nonnegative row is a cone with an empty G, -a as c and b as d.
+    let mut mats_g = Vec::new();
+    let mut vecs_h = Vec::new();
+    let mut vecs_c = Vec::new();
+    let mut scls_d = Vec::new();
+    let mut eq_rows = Vec::new();
+    // row of the totsu dual for each of our rows
+    let mut dual_index = vec![0; problem.n_rows()];
+    let mut totsu_row = 0;
+
+    let mut row = 0;
+    for cone in &problem.cones {
+        match *cone {
+            Cone::Zero(n) => eq_rows.extend(row..row + n),
+            Cone::NonNeg(n) => {
+                let rows = problem.a[row..row + n].iter().zip(&problem.b[row..row + n]);
+                for (dual, (a_row, &b)) in dual_index[row..row + n].iter_mut().zip(rows) {
+                    mats_g.push(MatBuild::new(MatType::General(0, n_vars)));
+                    vecs_h.push(MatBuild::new(MatType::General(0, 1)));
+                    let mut c = MatBuild::new(MatType::General(n_vars, 1));
+                    for (j, a) in a_row {
+                        c[(*j, 0)] = -a;
+                    }
+                    vecs_c.push(c);
+                    scls_d.push(b);
+                    *dual = totsu_row;
+                    totsu_row += 1;
+                }
+            }
+            Cone::SecondOrder(n) => {
+                let mut g = MatBuild::new(MatType::General(n - 1, n_vars));
+                let mut h = MatBuild::new(MatType::General(n - 1, 1));
+                let mut c = MatBuild::new(MatType::General(n_vars, 1));
+                for (j, a) in &problem.a[row] {
+                    c[(*j, 0)] = -a;
+                }
+                for k in 1..n {
+                    for (j, a) in &problem.a[row + k] {
+                        g[(k - 1, *j)] = -a;
+                    }
+                    h[(k - 1, 0)] = problem.b[row + k];
+                }
+                mats_g.push(g);
+                vecs_h.push(h);
+                vecs_c.push(c);
+                scls_d.push(problem.b[row]);
+                for dual in &mut dual_index[row..row + n] {
+                    *dual = totsu_row;
+                    totsu_row += 1;
+                }
+            }
+            Cone::Exp | Cone::Power(_) => {
+                return Err(SolverError::UnsupportedCone(Backend::Totsu, *cone))
+            }
+        }
+        row += cone.dim();
+    }
+
+    let mut mat_a = MatBuild::new(MatType::General(eq_rows.len(), n_vars));
+    let mut vec_b = MatBuild::new(MatType::General(eq_rows.len(), 1));
+    for (k, &r) in eq_rows.iter().enumerate() {
+        for (j, a) in &problem.a[r] {
+            mat_a[(k, *j)] = *a;
+        }
+        vec_b[(k, 0)] = problem.b[r];
+        dual_index[r] = totsu_row;
+        totsu_row += 1;
+    }
+
+    let mut prob = ProbSOCP::new(vec_f, mats_g, vecs_h, vecs_c, scls_d, mat_a, vec_b);
+
+    let mut solver = Solver::<La>::new();
+    solver = solver.par(|p| {
+        p.eps_acc = config.eps;
+        p.eps_inf = config.eps;
+        p.max_iter = Some(config.max_iter);
+        p.log_period = if config.verbose { 10000 } else { 0 };
+    });
+
+    let (op_c, op_a, op_b, cone, mut work) = prob.problem();
+    // totsu reports neither iterations nor certificates
+    let outcome = match solver.solve((op_c, op_a, op_b, cone, &mut work)) {
+        Ok((x, y)) => {
+            let solution = Solution {
+                x: x[..n_vars].to_vec(),
+                y: dual_index.iter().map(|&i| y[i]).collect(),
+            };
+            let info = SolveInfo::measure(problem, &solution, None);
+            SolveOutcome::Optimal { solution, info }
+        }
+        Err(TotsuError::Infeasible) => SolveOutcome::Infeasible {
+            certificate: None,
+            info: SolveInfo::iterations(None),
+        },
+        Err(TotsuError::Unbounded) => SolveOutcome::Unbounded {
+            certificate: None,
+            info: SolveInfo::iterations(None),
+        },
+        Err(TotsuError::ExcessIter) => SolveOutcome::IterationLimit {
+            solution: None,
+            info: SolveInfo::iterations(Some(config.max_iter)),
+        },
+        Err(e) => SolveOutcome::NumericalError {
+            message: format!("{:?}", e),
+            info: SolveInfo::iterations(None),
+        },
+    };
+    Ok(outcome)
+}
+
+/// Column-compressed copy of the sparse rows, in one pass over the non-zeros.
+pub(crate) fn csc_columns(a: &[SparseRow], n_vars: usize) -> (Vec<usize>, Vec<usize>, Vec<f64>) {
+    let mut colptr = vec![0; n_vars + 1];
+    for row in a {
+        for (j, _) in row {
+            colptr[j + 1] += 1;
+        }
+    }
+    for j in 0..n_vars {
+        colptr[j + 1] += colptr[j];
+    }
+
+    // rows are visited in order, so every column comes out sorted by row
+    let nnz = colptr[n_vars];
+    let mut next = colptr.clone();
+    let mut rowval = vec![0; nnz];
+    let mut nzval = vec![0.0; nnz];
+    for (i, row) in a.iter().enumerate() {
+        for (j, v) in row {
+            rowval[next[*j]] = i;
+            nzval[next[*j]] = *v;
+            next[*j] += 1;
+        }
+    }
+    (colptr, rowval, nzval)
+}
+
+#[cfg(feature = "clarabel")]
+fn solve_clarabel(
+    problem: &ConicProblem,
+    config: &SolverConfig,
+) -> Result<SolveOutcome, SolverError> {
+    use clarabel::algebra::CscMatrix;
+    use clarabel::solver::*;
+
+    let n_vars = problem.n_vars;
+    let (colptr, rowval, nzval) = csc_columns(&problem.a, n_vars);
+    let mat_a = CscMatrix::new(problem.n_rows(), n_vars, colptr, rowval, nzval);
+    let mat_p = CscMatrix::zeros((n_vars, n_vars));
+
+    let cones: Vec<SupportedConeT<f64>> = problem
+        .cones
+        .iter()
+        .map(|cone| match *cone {
+            Cone::Zero(n) => ZeroConeT(n),
+            Cone::NonNeg(n) => NonnegativeConeT(n),
+            Cone::SecondOrder(n) => SecondOrderConeT(n),
+            Cone::Exp => ExponentialConeT(),
+            Cone::Power(alpha) => PowerConeT(alpha),
+        })
+        .collect();
+
+    let settings = DefaultSettingsBuilder::default()
+        .max_iter(config.max_iter as u32)
+        .tol_gap_abs(config.eps)
+        .tol_gap_rel(config.eps)
+        .tol_feas(config.eps)
+        .verbose(config.verbose)
+        .build()
+        .unwrap();
+
+    let mut solver = DefaultSolver::new(&mat_p, &problem.c, &mat_a, &problem.b, &cones, settings);
+    solver.solve();
+
+    let solution = Solution {
+        x: solver.solution.x.clone(),
+        y: solver.solution.z.clone(),
+    };
+    let info = SolveInfo::measure(problem, &solution, Some(solver.info.iterations as usize));
+
+    let outcome = match solver.solution.status {
+        SolverStatus::Solved | SolverStatus::AlmostSolved => {
+            SolveOutcome::Optimal { solution, info }
+        }
+        SolverStatus::PrimalInfeasible | SolverStatus::AlmostPrimalInfeasible => {
+            SolveOutcome::Infeasible {
+                certificate: Some(solution.y),
+                info,
+            }
+        }
+        SolverStatus::DualInfeasible | SolverStatus::AlmostDualInfeasible => {
+            SolveOutcome::Unbounded {
+                certificate: Some(solution.x),
+                info,
+            }
+        }
+        SolverStatus::MaxIterations | SolverStatus::MaxTime => SolveOutcome::IterationLimit {
+            solution: Some(solution),
+            info,
+        },
+        status => SolveOutcome::NumericalError {
+            message: format!("{:?}", status),
+            info,
+        },
+    };
+    Ok(outcome)
+}
+
+#[cfg(not(feature = "clarabel"))]
+fn solve_clarabel(_: &ConicProblem, _: &SolverConfig) -> Result<SolveOutcome, SolverError> {
+    Err(SolverError::BackendUnavailable(Backend::Clarabel))
+}
+
+#[cfg(feature = "scs")]
+fn solve_scs(problem: &ConicProblem, config: &SolverConfig) -> Result<SolveOutcome, SolverError> {
+    crate::scs::solve(problem, config)
+}
+
+#[cfg(not(feature = "scs"))]
+fn solve_scs(_: &ConicProblem, _: &SolverConfig) -> Result<SolveOutcome, SolverError> {
+    Err(SolverError::BackendUnavailable(Backend::Scs))
+}
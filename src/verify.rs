@@ -0,0 +1,247 @@
+use ethers::types::Address;
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cfmm::{path_tokens, Cfmm};
+use crate::router::PoolTrade;
+
+/// Outcome of re-executing one pool's trade against its exact math.
+#[derive(Debug, Clone)]
+pub struct PoolCheck {
+    pub pool: Address,
+    // (before, after) value of the pool invariant; order books have none and
+    // are checked by re-executing the tendered amounts against the levels
+    pub invariant: Option<(f64, f64)>,
+    // relative amount by which the trade leaves the trading set, 0.0 if inside
+    pub violation: f64,
+}
+
+/// Solver output replayed against the pools it trades with.
+#[derive(Debug, Clone)]
+pub struct Verification {
+    pub pools: Vec<PoolCheck>,
+    // net amount per token, received - tendered over all pools
+    pub net: Vec<(Address, f64)>,
+    // profit the solver reported
+    pub predicted: f64,
+    // market value of `net`
+    pub realized: f64,
+}
+
+impl Verification {
+    pub fn worst(&self) -> Option<&PoolCheck> {
+        self.pools
+            .iter()
+            .max_by(|a, b| a.violation.total_cmp(&b.violation))
+    }
+
+    /// True when no pool invariant drops by more than `slack` (relative).
+    pub fn accepts(&self, slack: f64) -> bool {
+        self.pools.iter().all(|check| check.violation <= slack)
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.pools {
+            match check.invariant {
+                Some((before, after)) => writeln!(
+                    f,
+                    "Pool {}: invariant {:.12} -> {:.12}, violation {:.3e}",
+                    check.pool, before, after, check.violation
+                )?,
+                None => writeln!(
+                    f,
+                    "Pool {}: re-executed, violation {:.3e}",
+                    check.pool, check.violation
+                )?,
+            }
+        }
+        for (token, amount) in &self.net {
+            writeln!(f, "Net {}: {:.12}", token, amount)?;
+        }
+        write!(
+            f,
+            "Profit: predicted {:.12}, realized {:.12}",
+            self.predicted, self.realized
+        )
+    }
+}
+
+/// Replays `trades` (one per entry of `pools`, amounts in the order of
+/// `pool.tokens()`) against the exact pool math. `prices` values the net
+/// trade; tokens without a price count as zero.
+pub fn verify(
+    pools: &[Cfmm],
+    trades: &[PoolTrade],
+    prices: &HashMap<Address, f64>,
+    predicted: f64,
+) -> Verification {
+    let mut checks = Vec::with_capacity(pools.len());
+    let mut net: Vec<(Address, f64)> = Vec::new();
+
+    for (pool, trade) in pools.iter().zip(trades) {
+        checks.push(check_pool(pool, &trade.received, &trade.tendered));
+
+        for (k, token) in trade.tokens.iter().enumerate() {
+            let amount = trade.received[k] - trade.tendered[k];
+            match net.iter_mut().find(|(t, _)| t == token) {
+                Some((_, total)) => *total += amount,
+                None => net.push((*token, amount)),
+            }
+        }
+    }
+
+    let realized = net
+        .iter()
+        .map(|(token, amount)| amount * prices.get(token).unwrap_or(&0.0))
+        .sum();
+
+    Verification {
+        pools: checks,
+        net,
+        predicted,
+        realized,
+    }
+}
+
+/// Per-pool trades of pushing `amount_in` of `token_in` through `pools` in
+/// order, each hop paying in what the previous one paid out; `None` when a
+/// pool cannot fill what reaches it.
+pub fn path_trades(pools: &[Cfmm], token_in: Address, amount_in: f64) -> Option<Vec<PoolTrade>> {
+    let tokens = path_tokens(pools, token_in);
+    let mut amount = amount_in;
+    let mut trades = Vec::with_capacity(pools.len());
+
+    for (k, pool) in pools.iter().enumerate() {
+        let amount_out = pool.swap(tokens[k], tokens[k + 1], amount)?;
+        let pool_tokens = pool.tokens();
+        let received = pool_tokens
+            .iter()
+            .map(|t| if *t == tokens[k + 1] { amount_out } else { 0.0 })
+            .collect();
+        let tendered = pool_tokens
+            .iter()
+            .map(|t| if *t == tokens[k] { amount } else { 0.0 })
+            .collect();
+        trades.push(PoolTrade {
+            pool: pool.address(),
+            tokens: pool_tokens,
+            received,
+            tendered,
+        });
+        amount = amount_out;
+    }
+
+    Some(trades)
+}
+
+fn check_pool(pool: &Cfmm, received: &[f64], tendered: &[f64]) -> PoolCheck {
+    let (invariant, violation) = match pool {
+        Cfmm::UniV2(pool) => {
+            let fee = pool.fee();
+            let reserves = [
+                pool.reserve0.as_u128() as f64,
+                pool.reserve1.as_u128() as f64,
+            ];
+            let new = new_reserves(&reserves, fee, received, tendered);
+            let before = reserves[0] * reserves[1];
+            let after = new[0].max(0.0) * new[1].max(0.0);
+            (
+                Some((before, after)),
+                shortfall(before, after, &reserves, &new),
+            )
+        }
+        Cfmm::ConstantSum(pool) => {
+            let fee = pool.fee();
+            let reserves = [
+                pool.reserve0.as_u128() as f64,
+                pool.reserve1.as_u128() as f64,
+            ];
+            let new = new_reserves(&reserves, fee, received, tendered);
+            let before = pool.price * reserves[0] + reserves[1];
+            let after = pool.price * new[0].max(0.0) + new[1].max(0.0);
+
+            // the cap binds per trade, not only through the reserves
+            let available = [
+                pool.available_out(pool.token0),
+                pool.available_out(pool.token1),
+            ];
+            let over_cap = received
+                .iter()
+                .zip(&available)
+                .map(|(out, cap)| excess(*out, *cap, *cap))
+                .fold(0.0, f64::max);
+            (
+                Some((before, after)),
+                shortfall(before, after, &reserves, &new).max(over_cap),
+            )
+        }
+        Cfmm::Balancer(pool) => {
+            let fee = pool.fee();
+            let reserves: Vec<f64> = pool.reserves.iter().map(|r| r.as_u128() as f64).collect();
+            let new = new_reserves(&reserves, fee, received, tendered);
+            let geometric_mean = |r: &[f64]| {
+                r.iter()
+                    .zip(&pool.weights)
+                    .map(|(r, w)| r.max(0.0).powf(*w))
+                    .product::<f64>()
+            };
+            let before = geometric_mean(&reserves);
+            let after = geometric_mean(&new);
+            (
+                Some((before, after)),
+                shortfall(before, after, &reserves, &new),
+            )
+        }
+        Cfmm::OrderBook(book) => {
+            // token0 = base, token1 = quote; each side fills independently,
+            // and input past the book's depth is a violation of its own.
+            // Overfills count against the side's depth, as pool invariants
+            // do against the reserves.
+            let side = |token_in: Address, amount_in: f64| {
+                let depth = book.max_amount_in(token_in);
+                let out = book
+                    .get_amount_out(token_in, amount_in.min(depth))
+                    .unwrap_or(0.0);
+                (out, excess(amount_in, depth, depth))
+            };
+            let (quote_out, base_over) = side(book.base, tendered[0]);
+            let (base_out, quote_over) = side(book.quote, tendered[1]);
+            let overfill = excess(received[0], base_out, book.ask_depth())
+                .max(excess(received[1], quote_out, book.bid_depth()));
+            (None, overfill.max(base_over).max(quote_over))
+        }
+    };
+
+    PoolCheck {
+        pool: pool.address(),
+        invariant,
+        violation,
+    }
+}
+
+// R + fee * delta - lambda
+fn new_reserves(reserves: &[f64], fee: f64, received: &[f64], tendered: &[f64]) -> Vec<f64> {
+    reserves
+        .iter()
+        .enumerate()
+        .map(|(k, r)| r + fee * tendered[k] - received[k])
+        .collect()
+}
+
+// Relative drop of the invariant, or of a reserve driven below zero.
+fn shortfall(before: f64, after: f64, reserves: &[f64], new_reserves: &[f64]) -> f64 {
+    let overdrawn = reserves
+        .iter()
+        .zip(new_reserves)
+        .map(|(r, new)| (-new).max(0.0) / r.max(1.0))
+        .fold(0.0, f64::max);
+    (1.0 - after / before).max(overdrawn)
+}
+
+// Amount by which `taken` exceeds `limit`, relative to `size` (what that side
+// of the pool can pay out at most) when it is above one unit.
+fn excess(taken: f64, limit: f64, size: f64) -> f64 {
+    ((taken - limit) / size.max(1.0)).max(0.0)
+}